@@ -20,7 +20,12 @@ mod thinking_tool;
 mod web_search_tool;
 
 use crate::AgentTool;
+use anyhow::{Result, anyhow};
+use gpui::App;
 use language_model::{LanguageModelRequestTool, LanguageModelToolSchemaFormat};
+use project::{Project, ProjectPath};
+use std::path::Path;
+use util::paths::normalize_lexically;
 
 pub use context_server_registry::*;
 pub use copy_path_tool::*;
@@ -100,3 +105,131 @@ tools! {
     ThinkingTool,
     WebSearchTool,
 }
+
+/// Resolves a path provided by the model to a [`ProjectPath`].
+///
+/// The path may be absolute, start with a worktree's root name, or be relative to a worktree
+/// that already contains it. `..` components are resolved up front so that a path can't climb
+/// out of a worktree and then be matched against another worktree's root name.
+pub fn resolve_project_path(project: &Project, input_path: &str, cx: &App) -> Result<ProjectPath> {
+    let normalized_path = normalize_lexically(Path::new(input_path))
+        .map_err(|_| anyhow!("Path {input_path} escapes the project"))?;
+    project
+        .find_project_path(&normalized_path, cx)
+        .ok_or_else(|| anyhow!("Path {input_path} is outside the project"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gpui::{Entity, TestAppContext};
+    use project::FakeFs;
+    use serde_json::json;
+    use settings::SettingsStore;
+    use util::{path, rel_path::rel_path};
+
+    #[gpui::test]
+    async fn test_resolve_project_path_single_worktree(cx: &mut TestAppContext) {
+        init_test(cx);
+        let fs = FakeFs::new(cx.executor());
+        fs.insert_tree(path!("/root"), json!({ "src": { "main.rs": "" } }))
+            .await;
+        let project = Project::test(fs, [path!("/root").as_ref()], cx).await;
+
+        // Prefixed with the worktree root name.
+        let resolved = resolve(&project, "root/src/new", cx).unwrap();
+        assert_eq!(resolved.path.as_ref(), rel_path("src/new"));
+
+        // Relative to the worktree, when the entry already exists.
+        let resolved = resolve(&project, "src/main.rs", cx).unwrap();
+        assert_eq!(resolved.path.as_ref(), rel_path("src/main.rs"));
+
+        // Absolute, inside the worktree.
+        let resolved = resolve(&project, path!("/root/src/new"), cx).unwrap();
+        assert_eq!(resolved.path.as_ref(), rel_path("src/new"));
+
+        // `..` is resolved before matching against the root name.
+        let resolved = resolve(&project, "root/src/../docs", cx).unwrap();
+        assert_eq!(resolved.path.as_ref(), rel_path("docs"));
+    }
+
+    #[gpui::test]
+    async fn test_resolve_project_path_multiple_worktrees(cx: &mut TestAppContext) {
+        init_test(cx);
+        let fs = FakeFs::new(cx.executor());
+        fs.insert_tree(
+            path!("/workspace"),
+            json!({
+                "frontend": { "index.ts": "" },
+                "backend": { "main.rs": "" },
+            }),
+        )
+        .await;
+        let project = Project::test(
+            fs,
+            [
+                path!("/workspace/frontend").as_ref(),
+                path!("/workspace/backend").as_ref(),
+            ],
+            cx,
+        )
+        .await;
+        let worktree_ids = project.read_with(cx, |project, cx| {
+            project
+                .worktrees(cx)
+                .map(|worktree| worktree.read(cx).id())
+                .collect::<Vec<_>>()
+        });
+
+        let resolved = resolve(&project, "backend/src", cx).unwrap();
+        assert_eq!(resolved.worktree_id, worktree_ids[1]);
+        assert_eq!(resolved.path.as_ref(), rel_path("src"));
+
+        let resolved = resolve(&project, "main.rs", cx).unwrap();
+        assert_eq!(resolved.worktree_id, worktree_ids[1]);
+
+        let resolved = resolve(&project, path!("/workspace/frontend/lib"), cx).unwrap();
+        assert_eq!(resolved.worktree_id, worktree_ids[0]);
+        assert_eq!(resolved.path.as_ref(), rel_path("lib"));
+    }
+
+    #[gpui::test]
+    async fn test_resolve_project_path_rejects_outside_paths(cx: &mut TestAppContext) {
+        init_test(cx);
+        let fs = FakeFs::new(cx.executor());
+        fs.insert_tree(path!("/root"), json!({})).await;
+        let project = Project::test(fs, [path!("/root").as_ref()], cx).await;
+
+        let error = resolve(&project, "root/../../etc", cx).unwrap_err();
+        assert!(
+            error.to_string().contains("escapes the project"),
+            "unexpected error: {error}"
+        );
+
+        let error = resolve(&project, path!("/elsewhere/file"), cx).unwrap_err();
+        assert!(
+            error.to_string().contains("outside the project"),
+            "unexpected error: {error}"
+        );
+
+        // Neither a root name nor an existing entry.
+        assert!(resolve(&project, "other/file", cx).is_err());
+    }
+
+    fn resolve(
+        project: &Entity<Project>,
+        input_path: &str,
+        cx: &mut TestAppContext,
+    ) -> Result<ProjectPath> {
+        project.read_with(cx, |project, cx| {
+            resolve_project_path(project, input_path, cx)
+        })
+    }
+
+    fn init_test(cx: &mut TestAppContext) {
+        cx.update(|cx| {
+            let settings_store = SettingsStore::test(cx);
+            cx.set_global(settings_store);
+        });
+    }
+}
@@ -1,9 +1,9 @@
-use agent_client_protocol::ToolKind;
+use agent_client_protocol::{self as acp, ToolKind};
 use agent_settings::AgentSettings;
 use anyhow::{Context as _, Result, anyhow};
 use futures::FutureExt as _;
 use gpui::{App, Entity, SharedString, Task};
-use project::Project;
+use project::{Project, ProjectPath};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use settings::Settings;
@@ -12,6 +12,7 @@ use util::markdown::MarkdownInlineCode;
 
 use crate::{
     AgentTool, ToolCallEventStream, ToolPermissionDecision, decide_permission_from_settings,
+    resolve_project_path,
 };
 
 /// Creates a new directory at the specified path within the project. Returns confirmation that the directory was created.
@@ -51,7 +52,7 @@ impl AgentTool for CreateDirectoryTool {
     }
 
     fn kind() -> ToolKind {
-        ToolKind::Read
+        ToolKind::Edit
     }
 
     fn initial_title(
@@ -72,6 +73,11 @@ impl AgentTool for CreateDirectoryTool {
         event_stream: ToolCallEventStream,
         cx: &mut App,
     ) -> Task<Result<Self::Output>> {
+        let project_path = match resolve_project_path(self.project.read(cx), &input.path, cx) {
+            Ok(project_path) => project_path,
+            Err(error) => return Task::ready(Err(error)),
+        };
+
         let settings = AgentSettings::get_global(cx);
         let decision = decide_permission_from_settings(Self::name(), &input.path, settings);
 
@@ -86,33 +92,278 @@ impl AgentTool for CreateDirectoryTool {
             )),
         };
 
-        let project_path = match self.project.read(cx).find_project_path(&input.path, cx) {
-            Some(project_path) => project_path,
-            None => {
-                return Task::ready(Err(anyhow!("Path to create was outside the project")));
-            }
+        let project = self.project.read(cx);
+        let path_style = project.path_style(cx);
+        let display_path_for = |project_path: &ProjectPath| {
+            project
+                .short_full_path_for_project_path(project_path, cx)
+                .unwrap_or_else(|| project_path.path.display(path_style).to_string())
         };
+        // Once an ancestor exists on disk, all of its ancestors do too.
+        let mut directories = project_path
+            .path
+            .ancestors()
+            .filter(|ancestor| !ancestor.is_empty())
+            .map(|ancestor| ProjectPath {
+                worktree_id: project_path.worktree_id,
+                path: ancestor.into_arc(),
+            })
+            .take_while(|ancestor| project.entry_for_path(ancestor, cx).is_none())
+            .map(|ancestor| {
+                let display_path = display_path_for(&ancestor);
+                (ancestor, display_path)
+            })
+            .collect::<Vec<_>>();
+        directories.reverse();
+        // An existing directory has no missing levels, but still goes through `create_entry`
+        // so the model hears back about it the same way.
+        if directories.is_empty() {
+            let display_path = display_path_for(&project_path);
+            directories.push((project_path, display_path));
+        }
         let destination_path: Arc<str> = input.path.as_str().into();
 
-        let create_entry = self.project.update(cx, |project, cx| {
-            project.create_entry(project_path.clone(), true, cx)
-        });
+        let project = self.project.clone();
 
-        cx.spawn(async move |_cx| {
+        cx.spawn(async move |cx| {
+            // Nothing may touch the filesystem until the user has approved the call.
             if let Some(authorize) = authorize {
                 authorize.await?;
             }
 
-            futures::select! {
-                result = create_entry.fuse() => {
-                    result.with_context(|| format!("Creating directory {destination_path}"))?;
-                }
-                _ = event_stream.cancelled_by_user().fuse() => {
-                    anyhow::bail!("Create directory cancelled by user");
+            // Create one level at a time so that progress can be reported as it happens.
+            let mut progress = Vec::<acp::ToolCallContent>::new();
+            let mut created = Vec::new();
+            for (directory, display_path) in directories {
+                let create_entry =
+                    project.update(cx, |project, cx| project.create_entry(directory, true, cx));
+
+                futures::select! {
+                    result = create_entry.fuse() => {
+                        result.with_context(|| format!("Creating directory {destination_path}"))?;
+                    }
+                    _ = event_stream.cancelled_by_user().fuse() => {
+                        anyhow::bail!("Create directory cancelled by user");
+                    }
                 }
+
+                progress.push(format!("Created {display_path}/").into());
+                event_stream
+                    .update_fields(acp::ToolCallUpdateFields::new().content(progress.clone()));
+                created.push(display_path);
             }
 
-            Ok(format!("Created directory {destination_path}"))
+            Ok(created_directories_message(&created))
         })
     }
 }
+
+/// Lists the directories that were created, each with a trailing `/`
+fn created_directories_message(display_paths: &[String]) -> String {
+    match display_paths {
+        [display_path] => format!("Created directory {display_path}/"),
+        display_paths => {
+            let display_paths = display_paths
+                .iter()
+                .map(|display_path| format!("{display_path}/"))
+                .collect::<Vec<_>>()
+                .join(", ");
+            format!("Created directories: {display_paths}")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fs::Fs;
+    use gpui::TestAppContext;
+    use project::FakeFs;
+    use serde_json::json;
+    use settings::SettingsStore;
+    use util::path;
+
+    fn init_test(cx: &mut TestAppContext) {
+        cx.update(|cx| {
+            let settings_store = SettingsStore::test(cx);
+            cx.set_global(settings_store);
+        });
+        cx.update(|cx| {
+            let mut settings = AgentSettings::get_global(cx).clone();
+            settings.always_allow_tool_actions = true;
+            AgentSettings::override_global(settings, cx);
+        });
+    }
+
+    #[gpui::test]
+    async fn test_create_directory_denied(cx: &mut TestAppContext) {
+        init_test(cx);
+        cx.update(|cx| {
+            let mut settings = AgentSettings::get_global(cx).clone();
+            settings.always_allow_tool_actions = false;
+            AgentSettings::override_global(settings, cx);
+        });
+
+        let fs = FakeFs::new(cx.executor());
+        fs.insert_tree(path!("/root"), json!({})).await;
+
+        let project = Project::test(fs.clone(), [path!("/root").as_ref()], cx).await;
+        let tool = Arc::new(CreateDirectoryTool::new(project));
+
+        let (event_stream, mut rx) = ToolCallEventStream::test();
+        let task = cx.update(|cx| {
+            tool.run(
+                CreateDirectoryToolInput {
+                    path: "root/new".into(),
+                },
+                event_stream,
+                cx,
+            )
+        });
+
+        let auth = rx.expect_authorization().await;
+        auth.response
+            .send(acp::PermissionOptionId::new("deny"))
+            .unwrap();
+
+        assert!(task.await.is_err());
+        assert!(!fs.is_dir(path!("/root/new").as_ref()).await);
+    }
+
+    #[gpui::test]
+    async fn test_create_directory_reports_progress(cx: &mut TestAppContext) {
+        init_test(cx);
+
+        let fs = FakeFs::new(cx.executor());
+        fs.insert_tree(path!("/root"), json!({})).await;
+
+        let project = Project::test(fs.clone(), [path!("/root").as_ref()], cx).await;
+        let tool = Arc::new(CreateDirectoryTool::new(project));
+
+        let (event_stream, mut rx) = ToolCallEventStream::test();
+        let output = cx
+            .update(|cx| {
+                tool.run(
+                    CreateDirectoryToolInput {
+                        path: "root/a/b/c".into(),
+                    },
+                    event_stream,
+                    cx,
+                )
+            })
+            .await
+            .unwrap();
+        assert_eq!(output, "Created directories: a/, a/b/, a/b/c/");
+
+        let expected_progress = ["Created a/", "Created a/b/", "Created a/b/c/"];
+        for level in 1..=expected_progress.len() {
+            let fields = rx.expect_update_fields().await;
+            assert_eq!(fields.title, None);
+            assert_eq!(
+                fields.content,
+                Some(
+                    expected_progress[..level]
+                        .iter()
+                        .map(|line| line.to_string().into())
+                        .collect::<Vec<acp::ToolCallContent>>()
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn test_create_directory_kind() {
+        assert_eq!(CreateDirectoryTool::kind(), ToolKind::Edit);
+    }
+
+    #[gpui::test]
+    async fn test_create_directory_reports_new_parents(cx: &mut TestAppContext) {
+        init_test(cx);
+
+        let fs = FakeFs::new(cx.executor());
+        fs.insert_tree(
+            path!("/root"),
+            json!({
+                "existing": {},
+            }),
+        )
+        .await;
+
+        let project = Project::test(fs.clone(), [path!("/root").as_ref()], cx).await;
+        let tool = Arc::new(CreateDirectoryTool::new(project));
+
+        let output = cx
+            .update(|cx| {
+                tool.clone().run(
+                    CreateDirectoryToolInput {
+                        path: "root/existing/a/b".into(),
+                    },
+                    ToolCallEventStream::test().0,
+                    cx,
+                )
+            })
+            .await
+            .unwrap();
+        assert_eq!(output, "Created directories: existing/a/, existing/a/b/");
+        assert!(fs.is_dir(path!("/root/existing/a/b").as_ref()).await);
+
+        let output = cx
+            .update(|cx| {
+                tool.clone().run(
+                    CreateDirectoryToolInput {
+                        path: "root/existing/c".into(),
+                    },
+                    ToolCallEventStream::test().0,
+                    cx,
+                )
+            })
+            .await
+            .unwrap();
+        assert_eq!(output, "Created directory existing/c/");
+    }
+
+    #[gpui::test]
+    async fn test_create_directory_path_resolution(cx: &mut TestAppContext) {
+        init_test(cx);
+
+        let fs = FakeFs::new(cx.executor());
+        fs.insert_tree(path!("/root"), json!({})).await;
+
+        let project = Project::test(fs.clone(), [path!("/root").as_ref()], cx).await;
+        let tool = Arc::new(CreateDirectoryTool::new(project));
+
+        let error = cx
+            .update(|cx| {
+                tool.clone().run(
+                    CreateDirectoryToolInput {
+                        path: "root/../../etc".into(),
+                    },
+                    ToolCallEventStream::test().0,
+                    cx,
+                )
+            })
+            .await
+            .unwrap_err();
+        assert!(
+            error.to_string().contains("escapes the project"),
+            "unexpected error: {error}"
+        );
+        assert!(!fs.is_dir(path!("/etc").as_ref()).await);
+
+        let absolute_path = path!("/root/absolute");
+        let output = cx
+            .update(|cx| {
+                tool.clone().run(
+                    CreateDirectoryToolInput {
+                        path: absolute_path.into(),
+                    },
+                    ToolCallEventStream::test().0,
+                    cx,
+                )
+            })
+            .await
+            .unwrap();
+        assert_eq!(output, "Created directory absolute/");
+        assert!(fs.is_dir(absolute_path.as_ref()).await);
+    }
+}
@@ -15,6 +15,7 @@ use util::paths::PathMatcher;
 ///
 /// - Supports glob patterns like "**/*.js" or "src/**/*.ts"
 /// - Returns matching file paths sorted alphabetically
+/// - Set `fuzzy` when you only know roughly what a file is called; results are then ranked by how well they match
 /// - Prefer the `grep` tool to this tool when searching for symbols unless you have specific information about paths.
 /// - Use this tool when you need to find files by name patterns
 /// - Results are paginated with 50 matches per page. Use the optional 'offset' parameter to request subsequent pages.
@@ -36,6 +37,18 @@ pub struct FindPathToolInput {
     /// When not provided, starts from the beginning.
     #[serde(default)]
     pub offset: usize,
+    /// Whether to also match paths that are excluded by `.gitignore`, such as build output or `node_modules`.
+    /// Defaults to false; only enable this when the file you're looking for is likely to be ignored.
+    #[serde(default)]
+    pub include_ignored: bool,
+    /// Whether to treat `glob` as a fuzzy query, like the file finder, instead of a glob.
+    /// Fuzzy matches are ranked best first rather than sorted alphabetically.
+    ///
+    /// <example>
+    /// A fuzzy query of "cucmbr" matches "tests/cucumber_test.rs"
+    /// </example>
+    #[serde(default)]
+    pub fuzzy: bool,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -43,6 +56,9 @@ pub struct FindPathToolOutput {
     offset: usize,
     current_matches_page: Vec<PathBuf>,
     all_matches_len: usize,
+    /// Whether only the best fuzzy matches were kept and others were left out.
+    #[serde(default)]
+    capped: bool,
 }
 
 impl From<FindPathToolOutput> for LanguageModelToolResultContent {
@@ -50,7 +66,14 @@ impl From<FindPathToolOutput> for LanguageModelToolResultContent {
         if output.current_matches_page.is_empty() {
             "No matches found".into()
         } else {
-            let mut llm_output = format!("Found {} total matches.", output.all_matches_len);
+            let mut llm_output = if output.capped {
+                format!(
+                    "Found more than {0} matches, keeping only the best {0}. Refine the query if the path you want isn't among them.",
+                    output.all_matches_len
+                )
+            } else {
+                format!("Found {} total matches.", output.all_matches_len)
+            };
             if output.all_matches_len > RESULTS_PER_PAGE {
                 write!(
                     &mut llm_output,
@@ -71,6 +94,7 @@ impl From<FindPathToolOutput> for LanguageModelToolResultContent {
 }
 
 const RESULTS_PER_PAGE: usize = 50;
+const MAX_FUZZY_MATCHES: usize = 200;
 
 pub struct FindPathTool {
     project: Entity<Project>,
@@ -112,10 +136,28 @@ impl AgentTool for FindPathTool {
         event_stream: ToolCallEventStream,
         cx: &mut App,
     ) -> Task<Result<FindPathToolOutput>> {
-        let search_paths_task = search_paths(&input.glob, self.project.clone(), cx);
+        let options = SearchPathsOptions {
+            include_ignored: input.include_ignored,
+            ..SearchPathsOptions::default()
+        };
+        let search_paths_task = if input.fuzzy {
+            fuzzy_search_paths(&input.glob, options, self.project.clone(), cx).boxed()
+        } else {
+            search_paths(&input.glob, options, self.project.clone(), cx)
+                .map(|paths| {
+                    paths.map(|paths| PathMatches {
+                        paths,
+                        capped: false,
+                    })
+                })
+                .boxed()
+        };
 
         cx.background_spawn(async move {
-            let matches = futures::select! {
+            let PathMatches {
+                paths: matches,
+                capped,
+            } = futures::select! {
                 result = search_paths_task.fuse() => result?,
                 _ = event_stream.cancelled_by_user().fuse() => {
                     anyhow::bail!("Path search cancelled by user");
@@ -152,12 +194,38 @@ impl AgentTool for FindPathTool {
                 offset: input.offset,
                 current_matches_page: paginated_matches.to_vec(),
                 all_matches_len: matches.len(),
+                capped,
             })
         })
     }
 }
 
-fn search_paths(glob: &str, project: Entity<Project>, cx: &mut App) -> Task<Result<Vec<PathBuf>>> {
+#[derive(Debug, Clone, Copy)]
+struct SearchPathsOptions {
+    /// Whether to match entries excluded by `.gitignore`.
+    include_ignored: bool,
+    /// Whether to match hidden entries, such as dotfiles.
+    include_hidden: bool,
+    /// Stop searching once this many paths have matched.
+    max_results: Option<usize>,
+}
+
+impl Default for SearchPathsOptions {
+    fn default() -> Self {
+        Self {
+            include_ignored: false,
+            include_hidden: true,
+            max_results: None,
+        }
+    }
+}
+
+fn search_paths(
+    glob: &str,
+    options: SearchPathsOptions,
+    project: Entity<Project>,
+    cx: &mut App,
+) -> Task<Result<Vec<PathBuf>>> {
     let path_style = project.read(cx).path_style(cx);
     let path_matcher = match PathMatcher::new(
         [
@@ -169,23 +237,119 @@ fn search_paths(glob: &str, project: Entity<Project>, cx: &mut App) -> Task<Resu
         Ok(matcher) => matcher,
         Err(err) => return Task::ready(Err(anyhow!("Invalid glob: {err}"))),
     };
-    let snapshots: Vec<_> = project
-        .read(cx)
-        .worktrees(cx)
-        .map(|worktree| worktree.read(cx).snapshot())
-        .collect();
-
-    cx.background_spawn(async move {
-        let mut results = Vec::new();
-        for snapshot in snapshots {
-            for entry in snapshot.entries(false, 0) {
-                if path_matcher.is_match(&snapshot.root_name().join(&entry.path)) {
-                    results.push(snapshot.absolutize(&entry.path));
+    let snapshots = worktree_snapshots(project, cx);
+
+    cx.spawn(async move |cx| {
+        let snapshots = snapshots.await;
+        cx.background_spawn(async move {
+            let mut results = Vec::new();
+            'snapshots: for snapshot in snapshots {
+                for entry in snapshot.entries(options.include_ignored, 0) {
+                    if !options.include_hidden && entry.is_hidden {
+                        continue;
+                    }
+                    if path_matcher.is_match(&snapshot.root_name().join(&entry.path)) {
+                        results.push(snapshot.absolutize(&entry.path));
+                        if options
+                            .max_results
+                            .is_some_and(|max_results| results.len() >= max_results)
+                        {
+                            break 'snapshots;
+                        }
+                    }
                 }
             }
-        }
 
-        Ok(results)
+            Ok(results)
+        })
+        .await
+    })
+}
+
+struct PathMatches {
+    paths: Vec<PathBuf>,
+    /// Whether more paths matched than were kept.
+    capped: bool,
+}
+
+/// Ranks every file in the project against `query` using the same fuzzy matcher as the file
+/// finder, returning the best matches first.
+fn fuzzy_search_paths(
+    query: &str,
+    options: SearchPathsOptions,
+    project: Entity<Project>,
+    cx: &mut App,
+) -> Task<Result<PathMatches>> {
+    let query = query.to_string();
+    let snapshots = worktree_snapshots(project, cx);
+
+    cx.spawn(async move |cx| {
+        let snapshots = snapshots.await;
+        cx.background_spawn(async move {
+            let max_results = options.max_results.unwrap_or(MAX_FUZZY_MATCHES);
+            // Ask for one more match than is kept, to tell whether the results are capped.
+            let mut matches = snapshots
+                .iter()
+                .flat_map(|snapshot| {
+                    // Hidden files are left out up front so they can't crowd out visible ones.
+                    let candidates = snapshot
+                        .files(options.include_ignored, 0)
+                        .filter(|entry| options.include_hidden || !entry.is_hidden)
+                        .map(|entry| fuzzy::PathMatchCandidate {
+                            is_dir: false,
+                            path: &entry.path,
+                            char_bag: entry.char_bag,
+                        })
+                        .collect();
+                    fuzzy::match_fixed_path_set(
+                        candidates,
+                        snapshot.id().to_usize(),
+                        Some(snapshot.root_name().into()),
+                        &query,
+                        false,
+                        max_results + 1,
+                        snapshot.path_style(),
+                    )
+                })
+                .collect::<Vec<_>>();
+            matches.sort_unstable_by(|a, b| b.cmp(a));
+
+            let capped = matches.len() > max_results;
+            let paths = matches
+                .into_iter()
+                .take(max_results)
+                .filter_map(|mat| {
+                    let snapshot = snapshots
+                        .iter()
+                        .find(|snapshot| snapshot.id().to_usize() == mat.worktree_id)?;
+                    Some(snapshot.absolutize(&mat.path))
+                })
+                .collect();
+            Ok(PathMatches { paths, capped })
+        })
+        .await
+    })
+}
+
+/// Returns a snapshot of every worktree in the project.
+///
+/// Searching while a worktree is still being scanned would silently miss entries, so the
+/// snapshots are only taken once every local worktree has finished its scan.
+fn worktree_snapshots(project: Entity<Project>, cx: &mut App) -> Task<Vec<worktree::Snapshot>> {
+    let scans_complete = project
+        .read(cx)
+        .worktrees(cx)
+        .filter_map(|worktree| Some(worktree.read(cx).as_local()?.scan_complete()))
+        .collect::<Vec<_>>();
+
+    cx.spawn(async move |cx| {
+        futures::future::join_all(scans_complete).await;
+        project.read_with(cx, |project, cx| {
+            project
+                .worktrees(cx)
+                .map(|worktree| worktree.read(cx).snapshot())
+                .collect()
+        })
     })
 }
 
@@ -220,7 +384,14 @@ mod test {
         let project = Project::test(fs.clone(), [path!("/root").as_ref()], cx).await;
 
         let matches = cx
-            .update(|cx| search_paths("root/**/car*", project.clone(), cx))
+            .update(|cx| {
+                search_paths(
+                    "root/**/car*",
+                    SearchPathsOptions::default(),
+                    project.clone(),
+                    cx,
+                )
+            })
             .await
             .unwrap();
         assert_eq!(
@@ -232,7 +403,14 @@ mod test {
         );
 
         let matches = cx
-            .update(|cx| search_paths("**/car*", project.clone(), cx))
+            .update(|cx| {
+                search_paths(
+                    "**/car*",
+                    SearchPathsOptions::default(),
+                    project.clone(),
+                    cx,
+                )
+            })
             .await
             .unwrap();
         assert_eq!(
@@ -244,6 +422,212 @@ mod test {
         );
     }
 
+    #[gpui::test]
+    async fn test_find_path_include_ignored(cx: &mut TestAppContext) {
+        init_test(cx);
+
+        let fs = FakeFs::new(cx.executor());
+        fs.insert_tree(
+            path!("/root"),
+            serde_json::json!({
+                ".git": {},
+                ".gitignore": "build.log\n",
+                "build.log": "",
+                "src": {
+                    "build.rs": "",
+                },
+            }),
+        )
+        .await;
+        let project = Project::test(fs.clone(), [path!("/root").as_ref()], cx).await;
+
+        let matches = cx
+            .update(|cx| {
+                search_paths(
+                    "**/build.*",
+                    SearchPathsOptions::default(),
+                    project.clone(),
+                    cx,
+                )
+            })
+            .await
+            .unwrap();
+        assert_eq!(matches, &[PathBuf::from(path!("/root/src/build.rs"))]);
+
+        let matches = cx
+            .update(|cx| {
+                search_paths(
+                    "**/build.*",
+                    SearchPathsOptions {
+                        include_ignored: true,
+                        ..SearchPathsOptions::default()
+                    },
+                    project.clone(),
+                    cx,
+                )
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            matches,
+            &[
+                PathBuf::from(path!("/root/build.log")),
+                PathBuf::from(path!("/root/src/build.rs"))
+            ]
+        );
+
+        let matches = cx
+            .update(|cx| {
+                search_paths(
+                    "**/build.*",
+                    SearchPathsOptions {
+                        include_ignored: true,
+                        max_results: Some(1),
+                        ..SearchPathsOptions::default()
+                    },
+                    project.clone(),
+                    cx,
+                )
+            })
+            .await
+            .unwrap();
+        assert_eq!(matches, &[PathBuf::from(path!("/root/build.log"))]);
+    }
+
+    #[gpui::test]
+    async fn test_find_path_waits_for_scan(cx: &mut TestAppContext) {
+        init_test(cx);
+
+        let fs = FakeFs::new(cx.executor());
+        fs.insert_tree(
+            path!("/root"),
+            serde_json::json!({
+                "a": { "b": { "c": { "needle.txt": "" } } },
+            }),
+        )
+        .await;
+        let project = Project::test(fs.clone(), [], cx).await;
+
+        project
+            .update(cx, |project, cx| {
+                project.find_or_create_worktree(path!("/root"), true, cx)
+            })
+            .await
+            .unwrap();
+
+        // Search right away, without waiting for the background scanner to finish.
+        let matches = cx
+            .update(|cx| search_paths("**/needle.txt", SearchPathsOptions::default(), project, cx))
+            .await
+            .unwrap();
+        assert_eq!(matches, &[PathBuf::from(path!("/root/a/b/c/needle.txt"))]);
+    }
+
+    #[gpui::test]
+    async fn test_find_path_fuzzy(cx: &mut TestAppContext) {
+        init_test(cx);
+
+        let fs = FakeFs::new(cx.executor());
+        fs.insert_tree(
+            path!("/root"),
+            serde_json::json!({
+                "curly_cumbersome_bar.rs": "",
+                "tests": {
+                    "cucumber_test.rs": "",
+                },
+                "unrelated.rs": "",
+            }),
+        )
+        .await;
+        let project = Project::test(fs.clone(), [path!("/root").as_ref()], cx).await;
+
+        let matches = cx
+            .update(|cx| {
+                fuzzy_search_paths("cucmbr", SearchPathsOptions::default(), project.clone(), cx)
+            })
+            .await
+            .unwrap()
+            .paths;
+        assert_eq!(
+            matches.first(),
+            Some(&PathBuf::from(path!("/root/tests/cucumber_test.rs")))
+        );
+        assert!(!matches.contains(&PathBuf::from(path!("/root/unrelated.rs"))));
+
+        // Both paths contain the query as a subsequence, but only one contains it verbatim.
+        let matches = cx
+            .update(|cx| {
+                fuzzy_search_paths(
+                    "cucumber",
+                    SearchPathsOptions::default(),
+                    project.clone(),
+                    cx,
+                )
+            })
+            .await
+            .unwrap()
+            .paths;
+        assert_eq!(
+            matches,
+            &[
+                PathBuf::from(path!("/root/tests/cucumber_test.rs")),
+                PathBuf::from(path!("/root/curly_cumbersome_bar.rs")),
+            ]
+        );
+    }
+
+    #[gpui::test]
+    async fn test_find_path_fuzzy_cap(cx: &mut TestAppContext) {
+        init_test(cx);
+
+        let fs = FakeFs::new(cx.executor());
+        fs.insert_tree(
+            path!("/root"),
+            serde_json::json!({
+                ".cucumber_1.rs": "",
+                ".cucumber_2.rs": "",
+                ".cucumber_3.rs": "",
+                "cucumber_a.rs": "",
+                "cucumber_b.rs": "",
+                "cucumber_c.rs": "",
+            }),
+        )
+        .await;
+        let project = Project::test(fs.clone(), [path!("/root").as_ref()], cx).await;
+
+        let search = |max_results, cx: &mut TestAppContext| {
+            cx.update(|cx| {
+                fuzzy_search_paths(
+                    "cucumber",
+                    SearchPathsOptions {
+                        include_hidden: false,
+                        max_results: Some(max_results),
+                        ..SearchPathsOptions::default()
+                    },
+                    project.clone(),
+                    cx,
+                )
+            })
+        };
+
+        // Hidden matches are filtered out before the results are capped.
+        let matches = search(2, cx).await.unwrap();
+        assert_eq!(matches.paths.len(), 2);
+        assert!(
+            matches
+                .paths
+                .iter()
+                .all(|path| !path.to_string_lossy().contains(".cucumber")),
+            "{:?}",
+            matches.paths
+        );
+        assert!(matches.capped);
+
+        let matches = search(3, cx).await.unwrap();
+        assert_eq!(matches.paths.len(), 3);
+        assert!(!matches.capped);
+    }
+
     fn init_test(cx: &mut TestAppContext) {
         cx.update(|cx| {
             let settings_store = SettingsStore::test(cx);
@@ -1,9 +1,9 @@
-use anyhow::{Result, anyhow};
+use anyhow::Result;
 use fs::Fs;
 use futures::{FutureExt, StreamExt, future::BoxFuture, stream::BoxStream};
 use futures::{Stream, TryFutureExt, stream};
 use gpui::{AnyView, App, AsyncApp, Context, CursorStyle, Entity, Task};
-use http_client::HttpClient;
+use http_client::{HttpClient, StatusCode};
 use language_model::{
     ApiKeyState, AuthenticateError, EnvVar, IconOrSvg, LanguageModel, LanguageModelCompletionError,
     LanguageModelCompletionEvent, LanguageModelId, LanguageModelName, LanguageModelProvider,
@@ -13,8 +13,9 @@ use language_model::{
 };
 use menu;
 use ollama::{
-    ChatMessage, ChatOptions, ChatRequest, ChatResponseDelta, OLLAMA_API_URL, OllamaFunctionCall,
-    OllamaFunctionTool, OllamaToolCall, get_models, show_model, stream_chat_completion,
+    ChatMessage, ChatOptions, ChatProvider, ChatRequest, ChatResponseDelta, DoneReason,
+    OLLAMA_API_URL, OllamaError, OllamaFunctionCall, OllamaFunctionTool, OllamaProvider,
+    OllamaToolCall,
 };
 pub use settings::OllamaAvailableModel as AvailableModel;
 use settings::{Settings, SettingsStore, update_settings_file};
@@ -42,13 +43,12 @@ static API_KEY_ENV_VAR: LazyLock<EnvVar> = env_var!(API_KEY_ENV_VAR_NAME);
 
 #[derive(Default, Debug, Clone, PartialEq)]
 pub struct OllamaSettings {
-    pub api_url: String,
+    pub api_url: Option<String>,
     pub auto_discover: bool,
     pub available_models: Vec<AvailableModel>,
 }
 
 pub struct OllamaLanguageModelProvider {
-    http_client: Arc<dyn HttpClient>,
     state: Entity<State>,
 }
 
@@ -96,52 +96,23 @@ impl State {
         })
     }
 
-    fn fetch_models(&mut self, cx: &mut Context<Self>) -> Task<Result<()>> {
-        let http_client = Arc::clone(&self.http_client);
+    /// A [`ChatProvider`] for the configured server, authenticated with its stored key
+    fn chat_provider(&self, cx: &App) -> Arc<dyn ChatProvider> {
         let api_url = OllamaLanguageModelProvider::api_url(cx);
         let api_key = self.api_key_state.key(&api_url);
+        Arc::new(OllamaProvider::new(
+            Arc::clone(&self.http_client),
+            api_url.to_string(),
+            api_key,
+        ))
+    }
+
+    fn fetch_models(&mut self, cx: &mut Context<Self>) -> Task<Result<()>> {
+        let provider = self.chat_provider(cx);
 
         // As a proxy for the server being "authenticated", we'll check if its up by fetching the models
         cx.spawn(async move |this, cx| {
-            let models = get_models(http_client.as_ref(), &api_url, api_key.as_deref()).await?;
-
-            let tasks = models
-                .into_iter()
-                // Since there is no metadata from the Ollama API
-                // indicating which models are embedding models,
-                // simply filter out models with "-embed" in their name
-                .filter(|model| !model.name.contains("-embed"))
-                .map(|model| {
-                    let http_client = Arc::clone(&http_client);
-                    let api_url = api_url.clone();
-                    let api_key = api_key.clone();
-                    async move {
-                        let name = model.name.as_str();
-                        let model =
-                            show_model(http_client.as_ref(), &api_url, api_key.as_deref(), name)
-                                .await?;
-                        let ollama_model = ollama::Model::new(
-                            name,
-                            None,
-                            model.context_length,
-                            Some(model.supports_tools()),
-                            Some(model.supports_vision()),
-                            Some(model.supports_thinking()),
-                        );
-                        Ok(ollama_model)
-                    }
-                });
-
-            // Rate-limit capability fetches
-            // since there is an arbitrary number of models available
-            let mut ollama_models: Vec<_> = futures::stream::iter(tasks)
-                .buffer_unordered(5)
-                .collect::<Vec<Result<_>>>()
-                .await
-                .into_iter()
-                .collect::<Result<Vec<_>>>()?;
-
-            ollama_models.sort_by(|a, b| a.name.cmp(&b.name));
+            let ollama_models = provider.models().await?;
 
             this.update(cx, |this, cx| {
                 this.fetched_models = ollama_models;
@@ -159,7 +130,6 @@ impl State {
 impl OllamaLanguageModelProvider {
     pub fn new(http_client: Arc<dyn HttpClient>, cx: &mut App) -> Self {
         let this = Self {
-            http_client: http_client.clone(),
             state: cx.new(|cx| {
                 cx.observe_global::<SettingsStore>({
                     let mut last_settings = OllamaLanguageModelProvider::settings(cx).clone();
@@ -195,16 +165,22 @@ impl OllamaLanguageModelProvider {
     }
 
     fn api_url(cx: &App) -> SharedString {
-        let api_url = &Self::settings(cx).api_url;
-        if api_url.is_empty() {
-            OLLAMA_API_URL.into()
-        } else {
-            SharedString::new(api_url.as_str())
-        }
+        resolve_api_url(
+            Self::settings(cx).api_url.as_deref(),
+            ollama::default_api_url,
+        )
     }
 
     fn has_custom_url(cx: &App) -> bool {
-        Self::settings(cx).api_url != OLLAMA_API_URL
+        Self::settings(cx).api_url.is_some()
+    }
+}
+
+/// Any URL set in the settings, even the default one, takes precedence over `OLLAMA_HOST`
+fn resolve_api_url(configured: Option<&str>, default: impl FnOnce() -> String) -> SharedString {
+    match configured.map(str::trim).filter(|url| !url.is_empty()) {
+        Some(url) => SharedString::new(url),
+        None => default().into(),
     }
 }
 
@@ -261,7 +237,6 @@ impl LanguageModelProvider for OllamaLanguageModelProvider {
                 Arc::new(OllamaLanguageModel {
                     id: LanguageModelId::from(model.name.clone()),
                     model,
-                    http_client: self.http_client.clone(),
                     request_limiter: RateLimiter::new(4),
                     state: self.state.clone(),
                 }) as Arc<dyn LanguageModel>
@@ -299,7 +274,6 @@ impl LanguageModelProvider for OllamaLanguageModelProvider {
 pub struct OllamaLanguageModel {
     id: LanguageModelId,
     model: ollama::Model,
-    http_client: Arc<dyn HttpClient>,
     request_limiter: RateLimiter,
     state: Entity<State>,
 }
@@ -334,6 +308,9 @@ impl OllamaLanguageModel {
                                 messages.push(ChatMessage::Tool {
                                     tool_name: tool_result.tool_name.to_string(),
                                     content: tool_result.content.to_str().unwrap_or("").to_string(),
+                                    // Zed makes up ids for calls Ollama sent without one, and
+                                    // the native API matches results by name anyway
+                                    tool_call_id: None,
                                 })
                             }
                             _ => unreachable!("Only tool result should be extracted"),
@@ -387,17 +364,12 @@ impl OllamaLanguageModel {
                 }),
             }
         }
+        let options = ChatOptions {
+            stop: Some(request.stop),
+            temperature: request.temperature.or(Some(1.0)),
+            ..Default::default()
+        };
         ChatRequest {
-            model: self.model.name.clone(),
-            messages,
-            keep_alive: self.model.keep_alive.clone().unwrap_or_default(),
-            stream: true,
-            options: Some(ChatOptions {
-                num_ctx: Some(self.model.max_tokens),
-                stop: Some(request.stop),
-                temperature: request.temperature.or(Some(1.0)),
-                ..Default::default()
-            }),
             think: self
                 .model
                 .supports_thinking
@@ -407,6 +379,7 @@ impl OllamaLanguageModel {
             } else {
                 vec![]
             },
+            ..ChatRequest::for_model(&self.model, messages, options)
         }
     }
 }
@@ -482,16 +455,15 @@ impl LanguageModel for OllamaLanguageModel {
     > {
         let request = self.to_ollama_request(request);
 
-        let http_client = self.http_client.clone();
-        let (api_key, api_url) = self.state.read_with(cx, |state, cx| {
-            let api_url = OllamaLanguageModelProvider::api_url(cx);
-            (state.api_key_state.key(&api_url), api_url)
-        });
+        let provider = self
+            .state
+            .read_with(cx, |state, cx| state.chat_provider(cx));
 
         let future = self.request_limiter.stream(async move {
-            let stream =
-                stream_chat_completion(http_client.as_ref(), &api_url, api_key.as_deref(), request)
-                    .await?;
+            let stream = provider
+                .stream_chat(request)
+                .await
+                .map_err(into_completion_error)?;
             let stream = map_to_language_model_completion_events(stream);
             Ok(stream)
         });
@@ -500,14 +472,45 @@ impl LanguageModel for OllamaLanguageModel {
     }
 }
 
+fn into_completion_error(error: OllamaError) -> LanguageModelCompletionError {
+    match error {
+        // Unlike `from_http_status`, keep the server's message, since it names the model
+        OllamaError::ModelNotFound(message) => LanguageModelCompletionError::HttpResponseError {
+            provider: PROVIDER_NAME,
+            status_code: StatusCode::NOT_FOUND,
+            message,
+        },
+        OllamaError::Unauthorized(message) => LanguageModelCompletionError::AuthenticationError {
+            provider: PROVIDER_NAME,
+            message,
+        },
+        OllamaError::ServerUnavailable(error) => LanguageModelCompletionError::HttpSend {
+            provider: PROVIDER_NAME,
+            error,
+        },
+        OllamaError::Http { status, body } => {
+            LanguageModelCompletionError::from_http_status(PROVIDER_NAME, status, body, None)
+        }
+        OllamaError::Read(error) => LanguageModelCompletionError::ApiReadResponseError {
+            provider: PROVIDER_NAME,
+            error,
+        },
+        OllamaError::Parse(error) => LanguageModelCompletionError::DeserializeResponse {
+            provider: PROVIDER_NAME,
+            error,
+        },
+        error => LanguageModelCompletionError::Other(error.into()),
+    }
+}
+
 fn map_to_language_model_completion_events(
-    stream: Pin<Box<dyn Stream<Item = anyhow::Result<ChatResponseDelta>> + Send>>,
+    stream: Pin<Box<dyn Stream<Item = Result<ChatResponseDelta, OllamaError>> + Send>>,
 ) -> impl Stream<Item = Result<LanguageModelCompletionEvent, LanguageModelCompletionError>> {
     // Used for creating unique tool use ids
     static TOOL_CALL_COUNTER: AtomicU64 = AtomicU64::new(0);
 
     struct State {
-        stream: Pin<Box<dyn Stream<Item = anyhow::Result<ChatResponseDelta>> + Send>>,
+        stream: Pin<Box<dyn Stream<Item = Result<ChatResponseDelta, OllamaError>> + Send>>,
         used_tools: bool,
     }
 
@@ -524,7 +527,7 @@ fn map_to_language_model_completion_events(
             let delta = match response {
                 Ok(delta) => delta,
                 Err(e) => {
-                    let event = Err(LanguageModelCompletionError::from(anyhow!(e)));
+                    let event = Err(into_completion_error(e));
                     return Some((vec![event], state));
                 }
             };
@@ -589,6 +592,10 @@ fn map_to_language_model_completion_events(
                 if state.used_tools {
                     state.used_tools = false;
                     events.push(Ok(LanguageModelCompletionEvent::Stop(StopReason::ToolUse)));
+                } else if delta.done_reason() == Some(DoneReason::Length) {
+                    events.push(Ok(LanguageModelCompletionEvent::Stop(
+                        StopReason::MaxTokens,
+                    )));
                 } else {
                     events.push(Ok(LanguageModelCompletionEvent::Stop(StopReason::EndTurn)));
                 }
@@ -706,7 +713,7 @@ impl ConfigurationView {
                 .as_mut()
                 .and_then(|models| models.ollama.as_mut())
             {
-                settings.api_url = Some(OLLAMA_API_URL.into());
+                settings.api_url = None;
             }
         });
         cx.notify();
@@ -776,7 +783,7 @@ impl ConfigurationView {
 
     fn render_api_url_editor(&self, cx: &Context<Self>) -> Div {
         let api_url = OllamaLanguageModelProvider::api_url(cx);
-        let custom_api_url_set = api_url != OLLAMA_API_URL;
+        let custom_api_url_set = OllamaLanguageModelProvider::has_custom_url(cx);
 
         if custom_api_url_set {
             h_flex()
@@ -818,6 +825,9 @@ impl ConfigurationView {
 impl Render for ConfigurationView {
     fn render(&mut self, _: &mut Window, cx: &mut Context<Self>) -> impl IntoElement {
         let is_authenticated = self.state.read(cx).is_authenticated();
+        // Installing Ollama only helps when the server is meant to run on this machine
+        let offer_download = !is_authenticated
+            && ollama::is_local_api_url(&OllamaLanguageModelProvider::api_url(cx));
 
         v_flex()
             .gap_2()
@@ -834,7 +844,7 @@ impl Render for ConfigurationView {
                             .w_full()
                             .gap_2()
                             .map(|this| {
-                                if is_authenticated {
+                                if !offer_download {
                                     this.child(
                                         Button::new("ollama-site", "Ollama")
                                             .style(ButtonStyle::Subtle)
@@ -950,6 +960,35 @@ fn tool_into_ollama(tool: LanguageModelRequestTool) -> ollama::OllamaTool {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_configured_api_url_takes_precedence_over_host() {
+        let host = || "http://gpu-box:11434".to_string();
+        assert_eq!(resolve_api_url(Some(OLLAMA_API_URL), host), OLLAMA_API_URL);
+        assert_eq!(
+            resolve_api_url(Some("http://other:8080"), host),
+            "http://other:8080"
+        );
+        assert_eq!(resolve_api_url(None, host), "http://gpu-box:11434");
+        assert_eq!(resolve_api_url(Some(""), host), "http://gpu-box:11434");
+    }
+
+    #[test]
+    fn test_model_not_found_keeps_status_and_message() {
+        let message = r#"{"error":"model \"llama9\" not found, try pulling it first"}"#;
+        let error = into_completion_error(OllamaError::ModelNotFound(message.to_string()));
+        match error {
+            LanguageModelCompletionError::HttpResponseError {
+                status_code,
+                message: error_message,
+                ..
+            } => {
+                assert_eq!(status_code, StatusCode::NOT_FOUND);
+                assert_eq!(error_message, message);
+            }
+            error => panic!("unexpected error: {error:?}"),
+        }
+    }
+
     #[test]
     fn test_merge_settings_preserves_display_names_for_similar_models() {
         // Regression test for https://github.com/zed-industries/zed/issues/43646
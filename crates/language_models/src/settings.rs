@@ -79,7 +79,7 @@ impl settings::Settings for AllLanguageModelSettings {
                 available_models: mistral.available_models.unwrap_or_default(),
             },
             ollama: OllamaSettings {
-                api_url: ollama.api_url.unwrap(),
+                api_url: ollama.api_url,
                 auto_discover: ollama.auto_discover.unwrap_or(true),
                 available_models: ollama.available_models.unwrap_or_default(),
             },
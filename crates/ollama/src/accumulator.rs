@@ -0,0 +1,438 @@
+//! Accumulators for the text, tool calls and token rate of a streamed chat response
+
+use serde_json::Value;
+use std::time::Instant;
+
+use crate::{ChatMessage, ChatResponseDelta, OllamaFunctionCall, OllamaToolCall};
+
+/// Tracks how quickly tokens arrive, smoothed with an exponential moving average
+#[derive(Default, Debug)]
+pub struct ThroughputMeter {
+    last_token_at: Option<Instant>,
+    tokens_per_second: Option<f64>,
+}
+
+impl ThroughputMeter {
+    /// Weight given to the newest interval
+    const SMOOTHING: f64 = 0.3;
+
+    /// Records `tokens` arriving at `now` and returns the smoothed rate
+    pub fn record(&mut self, now: Instant, tokens: u64) -> f64 {
+        if tokens > 0
+            && let Some(last_token_at) = self.last_token_at.replace(now)
+        {
+            let elapsed = now.duration_since(last_token_at).as_secs_f64();
+            if elapsed > 0.0 {
+                let rate = tokens as f64 / elapsed;
+                self.tokens_per_second = Some(match self.tokens_per_second {
+                    Some(previous) => previous + Self::SMOOTHING * (rate - previous),
+                    None => rate,
+                });
+            }
+        }
+        self.tokens_per_second.unwrap_or(0.0)
+    }
+}
+
+/// Text newly added by a single [`ChatResponseDelta`]
+#[derive(Default, Debug, PartialEq, Eq)]
+pub struct DeltaText {
+    pub content: String,
+    pub thinking: String,
+}
+
+/// How the text of successive deltas relates to the text before it
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DeltaMode {
+    /// Decided separately for content and thinking by their second non-empty delta, which
+    /// is taken as cumulative only if it strictly extends the first. Incremental text can
+    /// look cumulative by accident ("#" then "##"), so prefer naming the mode when known
+    Auto,
+    /// Each delta repeats all the text sent so far
+    Cumulative,
+    /// Each delta carries only the new text, as `/api/chat` does
+    #[default]
+    Incremental,
+}
+
+/// Reduces successive [`ChatResponseDelta`]s to the text each one adds.
+///
+/// In [`DeltaMode::Incremental`] every delta is appended as is. In
+/// [`DeltaMode::Cumulative`] a delta whose text extends everything seen so far only
+/// contributes the extension, and any other non-empty text replaces the accumulated text
+/// and is returned in full.
+#[derive(Default, Debug)]
+pub struct DeltaAccumulator {
+    content: String,
+    thinking: String,
+    content_mode: DeltaMode,
+    thinking_mode: DeltaMode,
+}
+
+impl DeltaAccumulator {
+    pub fn new(mode: DeltaMode) -> Self {
+        Self {
+            content_mode: mode,
+            thinking_mode: mode,
+            ..Self::default()
+        }
+    }
+
+    pub fn push(&mut self, delta: &ChatResponseDelta) -> DeltaText {
+        let ChatMessage::Assistant {
+            content, thinking, ..
+        } = &delta.message
+        else {
+            return DeltaText::default();
+        };
+
+        DeltaText {
+            content: Self::advance(&mut self.content, &mut self.content_mode, content),
+            thinking: Self::advance(
+                &mut self.thinking,
+                &mut self.thinking_mode,
+                thinking.as_deref().unwrap_or_default(),
+            ),
+        }
+    }
+
+    pub fn content(&self) -> &str {
+        &self.content
+    }
+
+    pub fn thinking(&self) -> &str {
+        &self.thinking
+    }
+
+    fn advance(accumulated: &mut String, mode: &mut DeltaMode, text: &str) -> String {
+        if text.is_empty() {
+            return String::new();
+        }
+        if *mode == DeltaMode::Auto && !accumulated.is_empty() {
+            *mode = if text.len() > accumulated.len() && text.starts_with(accumulated.as_str()) {
+                DeltaMode::Cumulative
+            } else {
+                DeltaMode::Incremental
+            };
+        }
+
+        match mode {
+            DeltaMode::Incremental => {
+                accumulated.push_str(text);
+                text.to_string()
+            }
+            DeltaMode::Auto | DeltaMode::Cumulative => {
+                let new_text = text
+                    .strip_prefix(accumulated.as_str())
+                    .unwrap_or(text)
+                    .to_string();
+                accumulated.clear();
+                accumulated.push_str(text);
+                new_text
+            }
+        }
+    }
+}
+
+/// Assembles the tool calls spread across the deltas of a chat response.
+///
+/// Fragments sharing an `id` are merged into one call. Calls without an `id`, as sent
+/// by servers older than v0.12.10, are merged by their position in `tool_calls` instead.
+#[derive(Default, Debug)]
+pub struct ToolCallAggregator {
+    tool_calls: Vec<(ToolCallKey, OllamaToolCall)>,
+}
+
+/// What identifies the fragments of one tool call across deltas
+#[derive(Debug, PartialEq, Eq)]
+enum ToolCallKey {
+    Id(String),
+    Index(usize),
+}
+
+impl ToolCallAggregator {
+    /// Adds the tool calls of a delta, returning the complete calls once the response is done
+    pub fn push(&mut self, delta: &ChatResponseDelta) -> Option<Vec<OllamaToolCall>> {
+        if let ChatMessage::Assistant {
+            tool_calls: Some(tool_calls),
+            ..
+        } = &delta.message
+        {
+            for (index, tool_call) in tool_calls.iter().enumerate() {
+                let key = tool_call
+                    .id
+                    .clone()
+                    .map_or(ToolCallKey::Index(index), ToolCallKey::Id);
+                let existing = self
+                    .tool_calls
+                    .iter_mut()
+                    .find(|(existing_key, _)| *existing_key == key);
+                match existing {
+                    Some((_, existing)) => Self::merge(&mut existing.function, &tool_call.function),
+                    None => self.tool_calls.push((key, tool_call.clone())),
+                }
+            }
+        }
+        delta.done.then(|| {
+            std::mem::take(&mut self.tool_calls)
+                .into_iter()
+                .map(|(_, tool_call)| tool_call)
+                .collect()
+        })
+    }
+
+    fn merge(function: &mut OllamaFunctionCall, fragment: &OllamaFunctionCall) {
+        if !fragment.name.is_empty() {
+            function.name = fragment.name.clone();
+        }
+        match (&mut function.arguments, &fragment.arguments) {
+            (Value::Object(arguments), Value::Object(new_arguments)) => {
+                arguments.extend(new_arguments.clone());
+            }
+            (_, Value::Null) => {}
+            (arguments, new_arguments) => *arguments = new_arguments.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::assistant_delta;
+    use std::time::Duration;
+
+    #[test]
+    fn delta_accumulator_prefix_growth() {
+        let mut accumulator = DeltaAccumulator::new(DeltaMode::Cumulative);
+        let text = ["The", "The quick", "The quick brown", "The quick brown", ""]
+            .into_iter()
+            .map(|content| accumulator.push(&assistant_delta(content, None)).content)
+            .collect::<Vec<_>>();
+
+        assert_eq!(text, ["The", " quick", " brown", "", ""]);
+        assert_eq!(accumulator.content(), "The quick brown");
+    }
+
+    #[test]
+    fn delta_accumulator_full_replacement() {
+        let mut accumulator = DeltaAccumulator::new(DeltaMode::Cumulative);
+        accumulator.push(&assistant_delta("Hello wor", None));
+        let text = accumulator.push(&assistant_delta("Goodbye", None));
+
+        assert_eq!(text.content, "Goodbye");
+        assert_eq!(accumulator.content(), "Goodbye");
+        assert_eq!(
+            accumulator.push(&assistant_delta("Goodbye!", None)).content,
+            "!"
+        );
+    }
+
+    #[test]
+    fn delta_accumulator_incremental() {
+        let mut accumulator = DeltaAccumulator::default();
+        let text = [
+            ("", Some("Hmm")),
+            ("", Some(", ok")),
+            ("Hel", None),
+            ("lo", None),
+            ("", None),
+            (" wor", None),
+            ("ld", None),
+        ]
+        .into_iter()
+        .map(|(content, thinking)| accumulator.push(&assistant_delta(content, thinking)))
+        .collect::<Vec<_>>();
+
+        assert_eq!(text[3].content, "lo");
+        assert_eq!(accumulator.content(), "Hello world");
+        assert_eq!(accumulator.thinking(), "Hmm, ok");
+
+        // A repeated token is new text, not a cumulative snapshot that didn't grow
+        let mut accumulator = DeltaAccumulator::new(DeltaMode::Auto);
+        for content in ["ha", "ha", "ha"] {
+            accumulator.push(&assistant_delta(content, None));
+        }
+        assert_eq!(accumulator.content(), "hahaha");
+
+        // Forcing the mode skips detection, even when deltas look cumulative
+        let mut accumulator = DeltaAccumulator::new(DeltaMode::Incremental);
+        for content in ["The", "The end"] {
+            accumulator.push(&assistant_delta(content, None));
+        }
+        assert_eq!(accumulator.content(), "TheThe end");
+    }
+
+    #[test]
+    fn delta_accumulator_interleaved_content_and_thinking() {
+        let mut accumulator = DeltaAccumulator::new(DeltaMode::Cumulative);
+
+        let text = accumulator.push(&assistant_delta("", Some("Let me")));
+        assert_eq!(
+            text,
+            DeltaText {
+                content: String::new(),
+                thinking: "Let me".to_string(),
+            }
+        );
+
+        let text = accumulator.push(&assistant_delta("", Some("Let me think")));
+        assert_eq!(text.thinking, " think");
+
+        let text = accumulator.push(&assistant_delta("Sure", Some("Let me think")));
+        assert_eq!(
+            text,
+            DeltaText {
+                content: "Sure".to_string(),
+                thinking: String::new(),
+            }
+        );
+
+        let text = accumulator.push(&assistant_delta("Sure, here", None));
+        assert_eq!(text.content, ", here");
+        assert_eq!(accumulator.content(), "Sure, here");
+        assert_eq!(accumulator.thinking(), "Let me think");
+    }
+
+    #[test]
+    fn delta_accumulator_defaults_to_incremental() {
+        // Markdown headings stream a token at a time, so the second delta can extend the
+        // first without the stream being cumulative
+        let mut accumulator = DeltaAccumulator::default();
+        let text = ["#", "##", " Title"]
+            .into_iter()
+            .map(|content| accumulator.push(&assistant_delta(content, None)).content)
+            .collect::<Vec<_>>();
+
+        assert_eq!(text, ["#", "##", " Title"]);
+        assert_eq!(accumulator.content(), "### Title");
+    }
+
+    fn tool_call_delta(tool_calls: Value, done: bool) -> ChatResponseDelta {
+        serde_json::from_value(serde_json::json!({
+            "model": "llama3.2:3b",
+            "created_at": "2025-04-28T20:02:02.140489Z",
+            "message": { "role": "assistant", "content": "", "tool_calls": tool_calls },
+            "done": done
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn tool_call_aggregator_single_call() {
+        let mut aggregator = ToolCallAggregator::default();
+        let tool_calls = aggregator
+            .push(&tool_call_delta(
+                serde_json::json!([{
+                    "id": "call_llama3.2:3b_145155",
+                    "function": { "name": "weather", "arguments": { "city": "london" } }
+                }]),
+                true,
+            ))
+            .unwrap();
+
+        assert_eq!(tool_calls.len(), 1);
+        assert_eq!(tool_calls[0].id.as_deref(), Some("call_llama3.2:3b_145155"));
+        assert_eq!(tool_calls[0].function.name, "weather");
+        assert_eq!(
+            tool_calls[0].function.arguments,
+            serde_json::json!({ "city": "london" })
+        );
+    }
+
+    #[test]
+    fn tool_call_aggregator_merges_fragments_by_id() {
+        let mut aggregator = ToolCallAggregator::default();
+        let fragments = [
+            serde_json::json!([{ "id": "a", "function": { "name": "weather", "arguments": {} } }]),
+            serde_json::json!([
+                { "id": "b", "function": { "name": "time", "arguments": {} } },
+                { "id": "a", "function": { "name": "", "arguments": { "city": "london" } } }
+            ]),
+            serde_json::json!([{ "id": "a", "function": { "name": "", "arguments": { "unit": "c" } } }]),
+        ];
+        for fragment in fragments {
+            assert!(aggregator.push(&tool_call_delta(fragment, false)).is_none());
+        }
+
+        let tool_calls = aggregator
+            .push(&tool_call_delta(serde_json::json!([]), true))
+            .unwrap();
+        assert_eq!(tool_calls.len(), 2);
+        assert_eq!(tool_calls[0].function.name, "weather");
+        assert_eq!(
+            tool_calls[0].function.arguments,
+            serde_json::json!({ "city": "london", "unit": "c" })
+        );
+        assert_eq!(tool_calls[1].id.as_deref(), Some("b"));
+    }
+
+    // Servers prior to v0.12.10 don't send ids, see `parse_tool_call_pre_0_12_10()`
+    #[test]
+    fn tool_call_aggregator_merges_fragments_without_ids() {
+        let mut aggregator = ToolCallAggregator::default();
+        let first = serde_json::json!([{
+            "function": { "name": "weather", "arguments": { "city": "london" } }
+        }]);
+        let second = serde_json::json!([{
+            "function": { "name": "", "arguments": { "unit": "c" } }
+        }]);
+        assert!(aggregator.push(&tool_call_delta(first, false)).is_none());
+        let tool_calls = aggregator.push(&tool_call_delta(second, true)).unwrap();
+
+        assert_eq!(tool_calls.len(), 1);
+        assert_eq!(tool_calls[0].id, None);
+        assert_eq!(tool_calls[0].function.name, "weather");
+        assert_eq!(
+            tool_calls[0].function.arguments,
+            serde_json::json!({ "city": "london", "unit": "c" })
+        );
+
+        // Calls at different positions stay separate
+        let calls = serde_json::json!([
+            { "function": { "name": "weather", "arguments": { "city": "london" } } },
+            { "function": { "name": "time", "arguments": {} } }
+        ]);
+        let tool_calls = aggregator.push(&tool_call_delta(calls, true)).unwrap();
+        assert_eq!(
+            tool_calls
+                .iter()
+                .map(|call| call.function.name.as_str())
+                .collect::<Vec<_>>(),
+            ["weather", "time"]
+        );
+
+        // The aggregator starts over for the next response
+        let tool_calls = aggregator
+            .push(&tool_call_delta(serde_json::json!([]), true))
+            .unwrap();
+        assert!(tool_calls.is_empty());
+    }
+
+    #[test]
+    fn throughput_meter_smooths_rate() {
+        let start = Instant::now();
+        let mut meter = ThroughputMeter::default();
+        assert_eq!(meter.record(start, 1), 0.0);
+
+        // A token every 100ms
+        let mut rate = 0.0;
+        for i in 1..=10 {
+            rate = meter.record(start + Duration::from_millis(100 * i), 1);
+        }
+        assert!((rate - 10.0).abs() < 0.01, "rate was {rate}");
+
+        // Speeding up to a token every 50ms moves the rate towards 20 without jumping there
+        let resumed = start + Duration::from_millis(1000);
+        let rate = meter.record(resumed + Duration::from_millis(50), 1);
+        assert!(rate > 10.0 && rate < 20.0, "rate was {rate}");
+        let mut rate = 0.0;
+        for i in 2..=30 {
+            rate = meter.record(resumed + Duration::from_millis(50 * i), 1);
+        }
+        assert!((rate - 20.0).abs() < 0.01, "rate was {rate}");
+
+        // Deltas without tokens report the current rate
+        let idle = meter.record(resumed + Duration::from_secs(10), 0);
+        assert_eq!(idle, rate);
+    }
+}
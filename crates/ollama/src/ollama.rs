@@ -1,11 +1,161 @@
-use anyhow::{Context as _, Result};
-use futures::{AsyncBufReadExt, AsyncReadExt, StreamExt, io::BufReader, stream::BoxStream};
-use http_client::{AsyncBody, HttpClient, HttpRequestExt, Method, Request as HttpRequest};
-use serde::{Deserialize, Serialize};
+mod accumulator;
+mod openai_compat;
+mod provider;
+mod stream;
+
+pub use accumulator::{
+    DeltaAccumulator, DeltaMode, DeltaText, ThroughputMeter, ToolCallAggregator,
+};
+pub use openai_compat::stream_openai_chat;
+#[cfg(any(test, feature = "test-support"))]
+pub use provider::FakeChatProvider;
+pub use provider::{ChatProvider, OllamaProvider};
+pub use stream::{
+    MAX_LINE_BYTES, OnParseError, SseDecoder, SseEvent, parse_ndjson_stream,
+    parse_ndjson_stream_with_max_line, parse_sse_stream,
+};
+#[cfg(feature = "record-streams")]
+pub use stream::{TeeReader, tee_to_file};
+use stream::{error_if_truncated, parse_chat_stream};
+
+use base64::Engine as _;
+use chrono::{DateTime, FixedOffset};
+use futures::{AsyncReadExt, FutureExt as _, StreamExt, future::Either, stream::BoxStream};
+use http_client::{
+    AsyncBody, HttpClient, HttpRequestExt, Method, Request as HttpRequest, StatusCode,
+};
+use parking_lot::Mutex;
+use semver::Version;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
 use serde_json::Value;
 pub use settings::KeepAlive;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use thiserror::Error;
 
 pub const OLLAMA_API_URL: &str = "http://localhost:11434";
+/// The environment variable the Ollama CLI reads to find its server
+pub const OLLAMA_HOST_ENV_VAR_NAME: &str = "OLLAMA_HOST";
+const OLLAMA_DEFAULT_PORT: u16 = 11434;
+
+/// Returns the URL of the server named by `OLLAMA_HOST`, or [`OLLAMA_API_URL`] when it
+/// isn't set
+pub fn default_api_url() -> String {
+    api_url_from_host(std::env::var(OLLAMA_HOST_ENV_VAR_NAME).ok().as_deref())
+}
+
+/// Normalizes an `OLLAMA_HOST` value, which may be anything from a bare port to a full
+/// URL, the same way the Ollama CLI does
+fn api_url_from_host(host: Option<&str>) -> String {
+    let Some(host) = host.map(str::trim).filter(|host| !host.is_empty()) else {
+        return OLLAMA_API_URL.to_string();
+    };
+    let (scheme, rest, default_port) = match host.split_once("://") {
+        Some(("https", rest)) => ("https", rest, 443),
+        Some((scheme, rest)) => (scheme, rest, 80),
+        None => ("http", host, OLLAMA_DEFAULT_PORT),
+    };
+    let (authority, path) = rest
+        .find('/')
+        .map_or((rest, ""), |index| rest.split_at(index));
+    let (hostname, port) = split_host_port(authority);
+    let hostname = if hostname.is_empty() {
+        "127.0.0.1"
+    } else {
+        hostname
+    };
+    let port = port.unwrap_or(default_port);
+    format!("{scheme}://{hostname}:{port}{}", path.trim_end_matches('/'))
+}
+
+/// Whether `api_url` points at this machine, whichever port the server listens on
+pub fn is_local_api_url(api_url: &str) -> bool {
+    let rest = api_url.split_once("://").map_or(api_url, |(_, rest)| rest);
+    let authority = rest.split('/').next().unwrap_or_default();
+    let (hostname, _) = split_host_port(authority);
+    matches!(
+        hostname.to_ascii_lowercase().as_str(),
+        "localhost" | "127.0.0.1" | "0.0.0.0" | "[::1]" | "[::]"
+    )
+}
+
+/// Splits `host:port`, leaving the brackets on IPv6 addresses such as `[::1]:11434`
+fn split_host_port(authority: &str) -> (&str, Option<u16>) {
+    match authority.rsplit_once(':') {
+        Some((hostname, port)) if !port.contains(']') => match port.parse() {
+            Ok(port) => (hostname, Some(port)),
+            Err(_) => (authority, None),
+        },
+        _ => (authority, None),
+    }
+}
+
+type Result<T, E = OllamaError> = std::result::Result<T, E>;
+
+#[derive(Error, Debug)]
+pub enum OllamaError {
+    /// The requested model is not available on the server (HTTP 404)
+    #[error("Ollama model not found: {0}")]
+    ModelNotFound(String),
+    /// The server rejected the API key (HTTP 401 or 403)
+    #[error("Unauthorized request to Ollama API: {0}")]
+    Unauthorized(String),
+    /// The request could not be delivered, e.g. because Ollama isn't running
+    #[error("Ollama server is unavailable")]
+    ServerUnavailable(#[source] anyhow::Error),
+    #[error("Failed to connect to Ollama API: {status} {body}")]
+    Http { status: StatusCode, body: String },
+    #[error("Unable to parse Ollama response")]
+    Parse(#[from] serde_json::Error),
+    #[error("Invalid image: {0}")]
+    InvalidImage(String),
+    #[error("Invalid arguments for tool {name}: {source}")]
+    InvalidToolArguments {
+        name: String,
+        source: serde_json::Error,
+    },
+    #[error("Ollama request was cancelled")]
+    Cancelled,
+    #[error("Invalid chat messages: {0}")]
+    InvalidMessages(String),
+    /// The response stream ended before the server reported that it was done
+    #[error("Ollama closed the response stream before it was done")]
+    Truncated,
+    /// The response stream stayed open but produced nothing for the given duration
+    #[error("Ollama stopped responding for {0:?}")]
+    IdleTimeout(Duration),
+    /// The server reported an error in the body of a successful response
+    #[error("Ollama API error: {0}")]
+    Api(String),
+    /// The response contained a line longer than the given number of bytes, so reading
+    /// stopped rather than buffering it
+    #[error("Ollama sent a line longer than {0} bytes")]
+    LineTooLong(usize),
+    /// Reading the response body failed, which ends the stream
+    #[error("Failed to read Ollama response")]
+    Read(#[source] std::io::Error),
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+impl OllamaError {
+    /// Whether a stream stops after yielding this error, rather than carrying on with the
+    /// rest of the response
+    fn ends_stream(&self) -> bool {
+        matches!(self, Self::LineTooLong(_) | Self::Read(_))
+    }
+
+    pub fn from_status(status: StatusCode, body: String) -> Self {
+        match status {
+            StatusCode::NOT_FOUND => Self::ModelNotFound(body),
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Self::Unauthorized(body),
+            _ => Self::Http { status, body },
+        }
+    }
+}
 
 #[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
 #[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
@@ -19,27 +169,148 @@ pub struct Model {
     pub supports_thinking: Option<bool>,
 }
 
+/// Default context length for unknown models.
+const DEFAULT_TOKENS: u64 = 4096;
+/// Magic number. Lets many Ollama models work with ~16GB of ram.
+/// Models that support context beyond 16k such as codestral (32k) or devstral (128k) will be clamped down to 16k
+pub const MAXIMUM_TOKENS: u64 = 16384;
+
+/// Context lengths of well-known models, keyed by model name without the tag.
+const MODEL_CONTEXT_LENGTHS: &[(&[&str], u64)] = &[
+    (&["granite-code", "phi", "tinyllama"], 2048),
+    (&["llama2", "stablelm2", "vicuna", "yi"], 4096),
+    (
+        &["aya", "codegemma", "gemma", "gemma2", "llama3", "starcoder"],
+        8192,
+    ),
+    (&["codellama", "starcoder2"], 16384),
+    (
+        &[
+            "codestral",
+            "dolphin-mixtral",
+            "llava",
+            "magistral",
+            "mistral",
+            "mixstral",
+            "qwen2",
+            "qwen2.5-coder",
+        ],
+        32768,
+    ),
+    (
+        &[
+            "cogito",
+            "command-r",
+            "deepseek-coder-v2",
+            "deepseek-r1",
+            "deepseek-v3",
+            "devstral",
+            "gemma3",
+            "gpt-oss",
+            "granite3.3",
+            "llama3.1",
+            "llama3.2",
+            "llama3.3",
+            "mistral-nemo",
+            "phi3",
+            "phi3.5",
+            "phi4",
+            "qwen3",
+            "yi-coder",
+        ],
+        128000,
+    ),
+    (&["qwen3-coder"], 256000),
+];
+
+static REGISTERED_MODEL_CONTEXT_LENGTHS: Mutex<Vec<(String, u64)>> = Mutex::new(Vec::new());
+
+/// Registers the context length of models whose name starts with `name_prefix`.
+///
+/// Registered prefixes take precedence over the built-in table, and the longest
+/// matching prefix wins when several apply.
+pub fn register_model_context(name_prefix: impl Into<String>, tokens: u64) {
+    let name_prefix = name_prefix.into();
+    let mut registered = REGISTERED_MODEL_CONTEXT_LENGTHS.lock();
+    registered.retain(|(prefix, _)| *prefix != name_prefix);
+    registered.push((name_prefix, tokens));
+}
+
 fn get_max_tokens(name: &str) -> u64 {
-    /// Default context length for unknown models.
-    const DEFAULT_TOKENS: u64 = 4096;
-    /// Magic number. Lets many Ollama models work with ~16GB of ram.
-    /// Models that support context beyond 16k such as codestral (32k) or devstral (128k) will be clamped down to 16k
-    const MAXIMUM_TOKENS: u64 = 16384;
-
-    match name.split(':').next().unwrap() {
-        "granite-code" | "phi" | "tinyllama" => 2048,
-        "llama2" | "stablelm2" | "vicuna" | "yi" => 4096,
-        "aya" | "codegemma" | "gemma" | "gemma2" | "llama3" | "starcoder" => 8192,
-        "codellama" | "starcoder2" => 16384,
-        "codestral" | "dolphin-mixtral" | "llava" | "magistral" | "mistral" | "mixstral"
-        | "qwen2" | "qwen2.5-coder" => 32768,
-        "cogito" | "command-r" | "deepseek-coder-v2" | "deepseek-r1" | "deepseek-v3"
-        | "devstral" | "gemma3" | "gpt-oss" | "granite3.3" | "llama3.1" | "llama3.2"
-        | "llama3.3" | "mistral-nemo" | "phi3" | "phi3.5" | "phi4" | "qwen3" | "yi-coder" => 128000,
-        "qwen3-coder" => 256000,
-        _ => DEFAULT_TOKENS,
-    }
-    .clamp(1, MAXIMUM_TOKENS)
+    get_max_tokens_with_limit(name, MAXIMUM_TOKENS)
+}
+
+/// Returns the context length to use for `name`, clamped to `maximum_tokens`.
+pub fn get_max_tokens_with_limit(name: &str, maximum_tokens: u64) -> u64 {
+    let registered = REGISTERED_MODEL_CONTEXT_LENGTHS
+        .lock()
+        .iter()
+        .filter(|(prefix, _)| name.starts_with(prefix.as_str()))
+        .max_by_key(|(prefix, _)| prefix.len())
+        .map(|(_, tokens)| *tokens);
+
+    let base_name = name
+        .split_once(':')
+        .map_or(name, |(base_name, _)| base_name);
+    registered
+        .or_else(|| {
+            MODEL_CONTEXT_LENGTHS
+                .iter()
+                .find(|(names, _)| names.contains(&base_name))
+                .map(|(_, tokens)| *tokens)
+        })
+        .unwrap_or(DEFAULT_TOKENS)
+        .clamp(1, maximum_tokens.max(1))
+}
+
+fn humanize_model_name(name: &str) -> String {
+    let (family, tag) = match name.split_once(':') {
+        Some((family, "latest")) => (family, None),
+        Some((family, tag)) => (family, Some(tag)),
+        None => (name, None),
+    };
+
+    let family_words = family
+        .split('-')
+        .filter(|word| !word.is_empty())
+        .map(|word| {
+            let mut chars = word.chars();
+            chars
+                .next()
+                .map(|first| first.to_uppercase().chain(chars).collect())
+                .unwrap_or_default()
+        });
+    let tag_words = tag
+        .into_iter()
+        .flat_map(|tag| tag.split('-'))
+        .filter(|word| !word.is_empty())
+        .map(|word| {
+            if is_parameter_size(word) {
+                let (count, unit) = word.split_at(word.len() - 1);
+                format!("{count}{}", unit.to_uppercase())
+            } else {
+                word.to_string()
+            }
+        });
+
+    family_words
+        .chain(tag_words)
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+/// Whether `tag` names a parameter count such as `7b`, `0.5b`, `8x7b` or `135m`
+fn is_parameter_size(tag: &str) -> bool {
+    let Some(count) = tag
+        .strip_suffix(['b', 'B'])
+        .or_else(|| tag.strip_suffix(['m', 'M']))
+    else {
+        return false;
+    };
+    count.starts_with(|c: char| c.is_ascii_digit())
+        && count
+            .chars()
+            .all(|c| c.is_ascii_digit() || c == '.' || c == 'x')
 }
 
 impl Model {
@@ -64,6 +335,18 @@ impl Model {
         }
     }
 
+    /// Builds a model from the details reported by `/api/show`
+    pub fn from_show(name: &str, details: &ModelShow) -> Self {
+        Self::new(
+            name,
+            None,
+            details.context_length,
+            Some(details.supports_tools()),
+            Some(details.supports_vision()),
+            Some(details.supports_thinking()),
+        )
+    }
+
     pub fn id(&self) -> &str {
         &self.name
     }
@@ -72,9 +355,70 @@ impl Model {
         self.display_name.as_ref().unwrap_or(&self.name)
     }
 
+    /// A friendlier name for the model, e.g. "Qwen2.5 Coder 7B" for `qwen2.5-coder:7b`.
+    ///
+    /// An explicitly configured display name is returned unchanged. Otherwise the `:latest`
+    /// tag is dropped, family words are title-cased and parameter sizes are upper-cased.
+    pub fn humanized_display_name(&self) -> String {
+        let derived_name = self.name.strip_suffix(":latest");
+        match &self.display_name {
+            Some(display_name) if Some(display_name.as_str()) != derived_name => {
+                display_name.clone()
+            }
+            _ => humanize_model_name(&self.name),
+        }
+    }
+
     pub fn max_token_count(&self) -> u64 {
         self.max_tokens
     }
+
+    /// Roughly estimates how many tokens `messages` will use, without asking the server.
+    ///
+    /// Text counts as one token per four characters, plus a small per-message overhead
+    /// and a fixed cost for each image. This is only meant for warning about requests
+    /// that are likely to exceed [`Self::max_token_count`].
+    pub fn estimate_tokens(&self, messages: &[ChatMessage]) -> u64 {
+        const TOKENS_PER_MESSAGE: u64 = 4;
+        const TOKENS_PER_IMAGE: u64 = 768;
+
+        let text_tokens = |text: &str| (text.chars().count() as u64).div_ceil(4);
+        messages
+            .iter()
+            .map(|message| {
+                let tokens = match message {
+                    ChatMessage::Assistant {
+                        content,
+                        tool_calls,
+                        images,
+                        thinking,
+                    } => {
+                        let tool_call_tokens = tool_calls
+                            .iter()
+                            .flatten()
+                            .map(|tool_call| {
+                                text_tokens(&tool_call.function.name)
+                                    + text_tokens(&tool_call.function.arguments.to_string())
+                            })
+                            .sum::<u64>();
+                        text_tokens(content)
+                            + text_tokens(thinking.as_deref().unwrap_or_default())
+                            + tool_call_tokens
+                            + images.as_ref().map_or(0, Vec::len) as u64 * TOKENS_PER_IMAGE
+                    }
+                    ChatMessage::User { content, images } => {
+                        text_tokens(content)
+                            + images.as_ref().map_or(0, Vec::len) as u64 * TOKENS_PER_IMAGE
+                    }
+                    ChatMessage::System { content } => text_tokens(content),
+                    ChatMessage::Tool {
+                        tool_name, content, ..
+                    } => text_tokens(tool_name) + text_tokens(content),
+                };
+                tokens + TOKENS_PER_MESSAGE
+            })
+            .sum()
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -98,10 +442,183 @@ pub enum ChatMessage {
     Tool {
         tool_name: String,
         content: String,
+        /// The [`OllamaToolCall::id`] of the call this is the result of
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        tool_call_id: Option<String>,
     },
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+impl ChatMessage {
+    /// Builds a user message from raw image bytes, encoding them as base64
+    pub fn user_with_images(content: impl Into<String>, images: Vec<Vec<u8>>) -> Self {
+        let images = images
+            .into_iter()
+            .map(|image| base64::engine::general_purpose::STANDARD.encode(image))
+            .collect::<Vec<_>>();
+        Self::User {
+            content: content.into(),
+            images: (!images.is_empty()).then_some(images),
+        }
+    }
+
+    /// Builds a user message from base64 images, which may be given as data URLs
+    pub fn user_with_encoded_images(
+        content: impl Into<String>,
+        images: Vec<String>,
+    ) -> Result<Self> {
+        let images = images
+            .iter()
+            .map(|image| normalize_base64_image(image))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self::User {
+            content: content.into(),
+            images: (!images.is_empty()).then_some(images),
+        })
+    }
+}
+
+/// Checks that a conversation is well-formed before sending it, since Ollama reports
+/// misplaced messages with confusing errors.
+///
+/// Every `Tool` message has to answer a call of the same tool, made by the closest
+/// preceding assistant message, and each call can only be answered once. A result is
+/// matched to its call by id when both carry one, and by the tool's name otherwise.
+pub fn validate_messages(messages: &[ChatMessage]) -> Result<()> {
+    let mut unanswered_tool_calls = Vec::new();
+    for (index, message) in messages.iter().enumerate() {
+        match message {
+            ChatMessage::Assistant { tool_calls, .. } => {
+                unanswered_tool_calls = tool_calls
+                    .iter()
+                    .flatten()
+                    .map(|tool_call| (tool_call.id.as_deref(), tool_call.function.name.as_str()))
+                    .collect();
+            }
+            ChatMessage::Tool {
+                tool_name,
+                tool_call_id,
+                ..
+            } => {
+                let Some(position) =
+                    unanswered_tool_calls
+                        .iter()
+                        .position(|(id, name)| match (id, tool_call_id) {
+                            (Some(id), Some(tool_call_id)) => {
+                                id == tool_call_id && name == tool_name
+                            }
+                            _ => name == tool_name,
+                        })
+                else {
+                    return Err(OllamaError::InvalidMessages(format!(
+                        "message {index} is a result for `{tool_name}`, \
+                         which the preceding assistant message didn't call"
+                    )));
+                };
+                unanswered_tool_calls.remove(position);
+            }
+            ChatMessage::User { .. } | ChatMessage::System { .. } => {
+                unanswered_tool_calls.clear();
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The largest image, once decoded, that [`validate_images`] accepts by default
+pub const MAX_IMAGE_BYTES: usize = 20 * 1024 * 1024;
+
+/// Checks the images in `messages` before they're sent to `model`, so that problems are
+/// reported up front instead of as a slow failure on the server. Every image must be a
+/// complete PNG or JPEG of at most `max_bytes`, and the model must support vision.
+pub fn validate_images(model: &Model, messages: &[ChatMessage], max_bytes: usize) -> Result<()> {
+    let images = messages
+        .iter()
+        .enumerate()
+        .filter_map(|(message_index, message)| match message {
+            ChatMessage::User { images, .. } | ChatMessage::Assistant { images, .. } => {
+                Some((message_index, images.as_ref()?))
+            }
+            ChatMessage::System { .. } | ChatMessage::Tool { .. } => None,
+        })
+        .flat_map(|(message_index, images)| {
+            images
+                .iter()
+                .enumerate()
+                .map(move |(image_index, image)| (message_index, image_index, image))
+        })
+        .collect::<Vec<_>>();
+    if images.is_empty() {
+        return Ok(());
+    }
+    if model.supports_vision != Some(true) {
+        return Err(OllamaError::InvalidImage(format!(
+            "{} doesn't support images",
+            model.name
+        )));
+    }
+
+    let problems = images
+        .into_iter()
+        .filter_map(|(message_index, image_index, image)| {
+            let problem = match base64::engine::general_purpose::STANDARD.decode(image.trim()) {
+                Err(_) => "isn't valid base64".to_string(),
+                Ok(bytes) if bytes.len() > max_bytes => {
+                    format!("is {} bytes, over the {max_bytes} byte limit", bytes.len())
+                }
+                Ok(bytes) if !is_complete_png(&bytes) && !is_complete_jpeg(&bytes) => {
+                    "isn't a complete PNG or JPEG".to_string()
+                }
+                Ok(_) => return None,
+            };
+            Some(format!(
+                "image {image_index} of message {message_index} {problem}"
+            ))
+        })
+        .collect::<Vec<_>>();
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(OllamaError::InvalidImage(problems.join("; ")))
+    }
+}
+
+/// Whether `bytes` start with the PNG signature and end with an `IEND` chunk
+fn is_complete_png(bytes: &[u8]) -> bool {
+    const SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+    // The `IEND` chunk type followed by its fixed CRC
+    const IEND: &[u8] = b"IEND\xae\x42\x60\x82";
+    bytes.len() >= SIGNATURE.len() + IEND.len()
+        && bytes.starts_with(SIGNATURE)
+        && bytes.ends_with(IEND)
+}
+
+/// Whether `bytes` start with a JPEG start-of-image marker and end with end-of-image
+fn is_complete_jpeg(bytes: &[u8]) -> bool {
+    bytes.len() >= 5 && bytes.starts_with(&[0xFF, 0xD8, 0xFF]) && bytes.ends_with(&[0xFF, 0xD9])
+}
+
+/// Strips a `data:` URL prefix from a base64 image and checks that it decodes
+pub fn normalize_base64_image(image: &str) -> Result<String> {
+    let encoded = match image.strip_prefix("data:") {
+        Some(data_url) => match data_url.split_once(',') {
+            Some((media_type, encoded)) if media_type.ends_with(";base64") => encoded,
+            _ => {
+                return Err(OllamaError::InvalidImage(
+                    "data URL is not base64-encoded".to_string(),
+                ));
+            }
+        },
+        None => image,
+    }
+    .trim();
+
+    base64::engine::general_purpose::STANDARD
+        .decode(encoded)
+        .map_err(|error| OllamaError::InvalidImage(error.to_string()))?;
+    Ok(encoded.to_string())
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct OllamaToolCall {
     // TODO: Remove `Option` after most users have updated to Ollama v0.12.10,
     // which was released on the 4th of November 2025
@@ -109,7 +626,19 @@ pub struct OllamaToolCall {
     pub function: OllamaFunctionCall,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+impl OllamaToolCall {
+    /// Deserializes the arguments the model passed into the tool's input type
+    pub fn parse_arguments<T: DeserializeOwned>(&self) -> Result<T> {
+        T::deserialize(&self.function.arguments).map_err(|source| {
+            OllamaError::InvalidToolArguments {
+                name: self.function.name.clone(),
+                source,
+            }
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct OllamaFunctionCall {
     pub name: String,
     pub arguments: Value,
@@ -139,14 +668,96 @@ pub struct ChatRequest {
     pub think: Option<bool>,
 }
 
+impl ChatRequest {
+    /// Builds a streaming request for `model`, taking `keep_alive` and `num_ctx` from the
+    /// model so callers can't forget them. Options set in `overrides` take precedence, and
+    /// [`Self::with_keep_alive`] overrides the model's `keep_alive` for this request only.
+    pub fn for_model(model: &Model, messages: Vec<ChatMessage>, overrides: ChatOptions) -> Self {
+        Self {
+            model: model.name.clone(),
+            messages,
+            stream: true,
+            keep_alive: model.keep_alive.clone().unwrap_or_default(),
+            options: Some(ChatOptions {
+                num_ctx: overrides.num_ctx.or(Some(model.max_tokens)),
+                ..overrides
+            }),
+            tools: Vec::new(),
+            think: None,
+        }
+    }
+
+    /// Keeps the model loaded for `keep_alive` after this request instead of the model's
+    /// default, e.g. [`KeepAlive::unload`] to free its memory as soon as the response is done
+    pub fn with_keep_alive(mut self, keep_alive: KeepAlive) -> Self {
+        self.keep_alive = keep_alive;
+        self
+    }
+
+    /// Sets `think`, unless the model isn't known to support thinking, because Ollama
+    /// rejects thinking requests for such models
+    pub fn with_think(mut self, think: bool, model: &Model) -> Self {
+        if model.supports_thinking == Some(true) {
+            self.think = Some(think);
+        } else {
+            if think {
+                log::warn!(
+                    "Not enabling thinking for {}, which doesn't support it",
+                    model.name
+                );
+            }
+            self.think = None;
+        }
+        self
+    }
+
+    pub fn enable_thinking(self, model: &Model) -> Self {
+        self.with_think(true, model)
+    }
+}
+
 // https://github.com/ollama/ollama/blob/main/docs/modelfile.md#valid-parameters-and-values
 #[derive(Serialize, Default, Debug)]
 pub struct ChatOptions {
     pub num_ctx: Option<u64>,
     pub num_predict: Option<isize>,
+    /// Left out when empty, which some server versions would otherwise choke on
+    #[serde(skip_serializing_if = "is_none_or_empty")]
     pub stop: Option<Vec<String>>,
     pub temperature: Option<f32>,
     pub top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub num_gpu: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub repeat_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub repeat_last_n: Option<i32>,
+}
+
+fn is_none_or_empty(stop: &Option<Vec<String>>) -> bool {
+    stop.as_ref().is_none_or(Vec::is_empty)
+}
+
+impl ChatOptions {
+    /// Sets the sequences that end the response when the model generates them
+    pub fn with_stop(mut self, stop: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.stop = Some(stop.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Caps `num_predict` so that the prompt and the response together fit in the model's
+    /// context window. An unset or negative (unlimited) `num_predict`, which is Ollama's
+    /// default, is set to whatever room the prompt leaves.
+    pub fn clamp_predict(&mut self, model: &Model, prompt_tokens: u64) {
+        let remaining = model.max_token_count().saturating_sub(prompt_tokens);
+        let remaining = isize::try_from(remaining).unwrap_or(isize::MAX);
+        self.num_predict = Some(match self.num_predict {
+            Some(num_predict) if num_predict >= 0 => num_predict.min(remaining),
+            _ => remaining,
+        });
+    }
 }
 
 #[derive(Deserialize, Debug)]
@@ -158,6 +769,279 @@ pub struct ChatResponseDelta {
     pub done: bool,
     pub prompt_eval_count: Option<u64>,
     pub eval_count: Option<u64>,
+    /// Malformed lines that were skipped while streaming the response, reported on the
+    /// final delta when parsing with [`OnParseError::Collect`]
+    #[serde(skip)]
+    pub skipped_lines: Vec<String>,
+}
+
+/// A request to `/api/generate`. Setting `suffix` asks the model to fill in the middle
+/// between `prompt` and `suffix`, which code models such as qwen2.5-coder support.
+#[derive(Serialize, Debug)]
+pub struct GenerateRequest {
+    pub model: String,
+    pub prompt: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub suffix: Option<String>,
+    /// The `context` of a previous response, to continue that conversation
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub context: Option<Vec<i64>>,
+    pub options: Option<ChatOptions>,
+    pub stream: bool,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct GenerateResponseDelta {
+    pub model: String,
+    pub created_at: String,
+    pub response: String,
+    pub done_reason: Option<String>,
+    pub done: bool,
+    pub prompt_eval_count: Option<u64>,
+    pub eval_count: Option<u64>,
+    /// An encoding of the conversation so far, sent with the final delta
+    pub context: Option<Vec<i64>>,
+}
+
+impl ChatResponseDelta {
+    /// Why generation stopped, reported by the final delta
+    pub fn done_reason(&self) -> Option<DoneReason> {
+        self.done_reason.as_deref().map(DoneReason::from)
+    }
+}
+
+/// Why the server stopped generating a response
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DoneReason {
+    /// The model finished its answer or hit a stop sequence
+    Stop,
+    /// The response reached `num_predict` or the context length
+    Length,
+    ToolCalls,
+    Other(String),
+}
+
+impl From<&str> for DoneReason {
+    fn from(reason: &str) -> Self {
+        match reason {
+            "stop" => Self::Stop,
+            "length" => Self::Length,
+            "tool_calls" => Self::ToolCalls,
+            other => Self::Other(other.to_string()),
+        }
+    }
+}
+
+/// Token counts and stop reason reported by the final delta of a chat response
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct CompletionStats {
+    pub prompt_eval_count: Option<u64>,
+    pub eval_count: Option<u64>,
+    pub done_reason: Option<DoneReason>,
+    /// Malformed lines the server sent that were skipped, if collected with
+    /// [`OnParseError::Collect`]
+    pub skipped_lines: Vec<String>,
+}
+
+/// A chat response delta split into the parts consumers render differently
+#[derive(Debug)]
+pub enum StreamItem {
+    Content(String),
+    Thinking(String),
+    ToolCall(OllamaToolCall),
+    Done(CompletionStats),
+}
+
+impl StreamItem {
+    fn from_delta(delta: ChatResponseDelta) -> Vec<StreamItem> {
+        let done_reason = delta.done_reason();
+        let mut items = Vec::new();
+        if let ChatMessage::Assistant {
+            content,
+            tool_calls,
+            thinking,
+            ..
+        } = delta.message
+        {
+            if let Some(thinking) = thinking.filter(|thinking| !thinking.is_empty()) {
+                items.push(StreamItem::Thinking(thinking));
+            }
+            if !content.is_empty() {
+                items.push(StreamItem::Content(content));
+            }
+            items.extend(tool_calls.into_iter().flatten().map(StreamItem::ToolCall));
+        }
+        if delta.done {
+            items.push(StreamItem::Done(CompletionStats {
+                prompt_eval_count: delta.prompt_eval_count,
+                eval_count: delta.eval_count,
+                done_reason,
+                skipped_lines: delta.skipped_lines,
+            }));
+        }
+        items
+    }
+}
+
+/// Splits each delta of a chat stream into [`StreamItem`]s
+pub fn into_items(
+    stream: BoxStream<'static, Result<ChatResponseDelta>>,
+) -> BoxStream<'static, Result<StreamItem>> {
+    stream
+        .flat_map(|delta| {
+            let items = match delta {
+                Ok(delta) => StreamItem::from_delta(delta).into_iter().map(Ok).collect(),
+                Err(error) => vec![Err(error)],
+            };
+            futures::stream::iter(items)
+        })
+        .boxed()
+}
+
+/// Writes the content of a chat stream to `writer` as it arrives, leaving out thinking,
+/// and returns the stats reported once the response is done
+pub async fn stream_to_writer<W: std::io::Write>(
+    stream: BoxStream<'static, Result<ChatResponseDelta>>,
+    mut writer: W,
+) -> Result<CompletionStats> {
+    let mut items = into_items(stream);
+    let mut stats = CompletionStats::default();
+    while let Some(item) = items.next().await {
+        match item? {
+            StreamItem::Content(content) => {
+                writer
+                    .write_all(content.as_bytes())
+                    .and_then(|()| writer.flush())
+                    .map_err(anyhow::Error::from)?;
+            }
+            StreamItem::Done(done) => stats = done,
+            StreamItem::Thinking(_) | StreamItem::ToolCall(_) => {}
+        }
+    }
+    Ok(stats)
+}
+
+/// Pairs each delta of a chat stream with the smoothed rate, in tokens per second, at
+/// which the response is being generated
+pub fn with_throughput(
+    stream: BoxStream<'static, Result<ChatResponseDelta>>,
+) -> BoxStream<'static, Result<(ChatResponseDelta, f64)>> {
+    with_throughput_clock(stream, Instant::now)
+}
+
+/// Like [`with_throughput`], reading the arrival time of each delta from `now`
+fn with_throughput_clock(
+    stream: BoxStream<'static, Result<ChatResponseDelta>>,
+    mut now: impl FnMut() -> Instant + Send + 'static,
+) -> BoxStream<'static, Result<(ChatResponseDelta, f64)>> {
+    let mut meter = ThroughputMeter::default();
+    stream
+        .map(move |delta| {
+            let delta = delta?;
+            let has_token = delta_has_token(&delta);
+            let tokens_per_second = meter.record(now(), u64::from(has_token));
+            Ok((delta, tokens_per_second))
+        })
+        .boxed()
+}
+
+/// Whether a delta carries generated output. Ollama streams roughly one token per delta;
+/// the final delta carries none
+fn delta_has_token(delta: &ChatResponseDelta) -> bool {
+    match &delta.message {
+        ChatMessage::Assistant {
+            content,
+            thinking,
+            tool_calls,
+            ..
+        } => {
+            !content.is_empty()
+                || thinking
+                    .as_ref()
+                    .is_some_and(|thinking| !thinking.is_empty())
+                || tool_calls.as_ref().is_some_and(|calls| !calls.is_empty())
+        }
+        _ => false,
+    }
+}
+
+/// Callbacks for the timing and size of streamed chat requests, so embedders can forward
+/// them to their own telemetry. Every callback defaults to doing nothing
+pub trait OllamaMetrics: Send + Sync {
+    /// The server accepted the request and started streaming, `elapsed` after it was sent
+    fn on_connect(&self, _elapsed: Duration) {}
+
+    /// The first generated token arrived, `elapsed` after the request was sent
+    fn on_first_token(&self, _elapsed: Duration) {}
+
+    /// The response finished
+    fn on_done(&self, _stats: ChatStreamStats) {}
+}
+
+/// An [`OllamaMetrics`] that ignores everything
+pub struct NoopMetrics;
+
+impl OllamaMetrics for NoopMetrics {}
+
+/// What a finished chat stream reports to [`OllamaMetrics::on_done`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChatStreamStats {
+    /// Tokens in the prompt, as counted by the server
+    pub prompt_tokens: Option<u64>,
+    /// Tokens generated in the response, as counted by the server
+    pub tokens: Option<u64>,
+    /// Time from sending the request to the final delta
+    pub duration: Duration,
+}
+
+/// Like [`stream_chat_completion`], reporting the request's progress to `metrics`
+pub async fn stream_chat_completion_with_metrics(
+    client: &dyn HttpClient,
+    api_url: &str,
+    api_key: Option<&str>,
+    request: ChatRequest,
+    metrics: Arc<dyn OllamaMetrics>,
+) -> Result<BoxStream<'static, Result<ChatResponseDelta>>> {
+    let sent_at = Instant::now();
+    let stream = stream_chat_completion(client, api_url, api_key, request).await?;
+    metrics.on_connect(sent_at.elapsed());
+
+    let mut seen_token = false;
+    Ok(stream
+        .inspect(move |delta| {
+            let Ok(delta) = delta else {
+                return;
+            };
+            if !seen_token && delta_has_token(delta) {
+                seen_token = true;
+                metrics.on_first_token(sent_at.elapsed());
+            }
+            if delta.done {
+                metrics.on_done(ChatStreamStats {
+                    prompt_tokens: delta.prompt_eval_count,
+                    tokens: delta.eval_count,
+                    duration: sent_at.elapsed(),
+                });
+            }
+        })
+        .boxed())
+}
+
+/// Ends a stream with an [`OllamaError::IdleTimeout`] error if no item arrives within
+/// `timeout` of the previous one. Unlike a total timeout, long generations are never
+/// cut off as long as the model keeps producing output
+pub fn with_idle_timeout<T: Send + 'static>(
+    stream: BoxStream<'static, Result<T>>,
+    timeout: Duration,
+) -> BoxStream<'static, Result<T>> {
+    futures::stream::unfold(Some(stream), move |stream| async move {
+        let mut stream = stream?;
+        match futures::future::select(stream.next(), smol::Timer::after(timeout)).await {
+            Either::Left((item, _)) => Some((item?, Some(stream))),
+            Either::Right(_) => Some((Err(OllamaError::IdleTimeout(timeout)), None)),
+        }
+    })
+    .boxed()
 }
 
 #[derive(Serialize, Deserialize)]
@@ -165,7 +1049,7 @@ pub struct LocalModelsResponse {
     pub models: Vec<LocalModelListing>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct LocalModelListing {
     pub name: String,
     pub modified_at: String,
@@ -174,6 +1058,52 @@ pub struct LocalModelListing {
     pub details: ModelDetails,
 }
 
+impl LocalModelListing {
+    /// The size of the model on disk in decimal units, e.g. "2.0 GB"
+    pub fn size_human(&self) -> String {
+        const UNITS: [&str; 4] = ["KB", "MB", "GB", "TB"];
+        if self.size < 1000 {
+            return format!("{} B", self.size);
+        }
+        let mut size = self.size as f64;
+        let mut unit = UNITS[0];
+        for next_unit in UNITS {
+            if size < 1000.0 {
+                break;
+            }
+            size /= 1000.0;
+            unit = next_unit;
+        }
+        format!("{size:.1} {unit}")
+    }
+
+    pub fn modified_at(&self) -> Option<DateTime<FixedOffset>> {
+        DateTime::parse_from_rfc3339(&self.modified_at).ok()
+    }
+}
+
+/// Sorts listings from most to least recently modified, with unparseable dates last
+pub fn sort_by_modified(models: &mut [LocalModelListing]) {
+    models.sort_by_cached_key(|model| std::cmp::Reverse(model.modified_at()));
+}
+
+/// Groups listings by model family, each group sorted by [`sort_by_modified`]
+pub fn group_by_family(
+    models: impl IntoIterator<Item = LocalModelListing>,
+) -> BTreeMap<String, Vec<LocalModelListing>> {
+    let mut families = BTreeMap::<String, Vec<LocalModelListing>>::new();
+    for model in models {
+        families
+            .entry(model.details.family.clone())
+            .or_default()
+            .push(model);
+    }
+    for models in families.values_mut() {
+        sort_by_modified(models);
+    }
+    families
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct LocalModel {
     pub modelfile: String,
@@ -182,7 +1112,7 @@ pub struct LocalModel {
     pub details: ModelDetails,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct ModelDetails {
     pub format: String,
     pub family: String,
@@ -276,11 +1206,67 @@ impl ModelShow {
     }
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct RunningModelsResponse {
+    pub models: Vec<RunningModel>,
+}
+
+/// A model currently loaded into memory, as listed by `/api/ps`
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RunningModel {
+    pub name: String,
+    /// Memory used by the model in bytes, including the part held in VRAM
+    pub size: u64,
+    /// Bytes of the model held in GPU memory
+    pub size_vram: u64,
+    /// When the model will be unloaded if it isn't used again
+    pub expires_at: DateTime<FixedOffset>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct VersionResponse {
+    pub version: String,
+}
+
+/// API features whose availability depends on the version of the Ollama server
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OllamaFeatures {
+    /// Tool calls carry an `id` (v0.12.10)
+    pub tool_call_ids: bool,
+    /// Chat requests accept the `think` parameter (v0.9.0)
+    pub thinking: bool,
+    /// Requests accept a JSON schema in `format` (v0.5.0)
+    pub structured_outputs: bool,
+}
+
+impl OllamaFeatures {
+    pub fn from_version(version: &Version) -> Self {
+        // Pre-releases of a version already ship its features
+        let release = Version::new(version.major, version.minor, version.patch);
+        Self {
+            tool_call_ids: release >= Version::new(0, 12, 10),
+            thinking: release >= Version::new(0, 9, 0),
+            structured_outputs: release >= Version::new(0, 5, 0),
+        }
+    }
+}
+
 pub async fn stream_chat_completion(
     client: &dyn HttpClient,
     api_url: &str,
     api_key: Option<&str>,
     request: ChatRequest,
+) -> Result<BoxStream<'static, Result<ChatResponseDelta>>> {
+    stream_chat_completion_with(client, api_url, api_key, request, OnParseError::default()).await
+}
+
+/// Like [`stream_chat_completion`], with control over how malformed lines are handled
+pub async fn stream_chat_completion_with(
+    client: &dyn HttpClient,
+    api_url: &str,
+    api_key: Option<&str>,
+    request: ChatRequest,
+    on_parse_error: OnParseError,
 ) -> Result<BoxStream<'static, Result<ChatResponseDelta>>> {
     let uri = format!("{api_url}/api/chat");
     let request = HttpRequest::builder()
@@ -290,65 +1276,349 @@ pub async fn stream_chat_completion(
         .when_some(api_key, |builder, api_key| {
             builder.header("Authorization", format!("Bearer {api_key}"))
         })
-        .body(AsyncBody::from(serde_json::to_string(&request)?))?;
-
-    let mut response = client.send(request).await?;
-    if response.status().is_success() {
-        let reader = BufReader::new(response.into_body());
-
-        Ok(reader
-            .lines()
-            .map(|line| match line {
-                Ok(line) => serde_json::from_str(&line).context("Unable to parse chat response"),
-                Err(e) => Err(e.into()),
-            })
-            .boxed())
-    } else {
-        let mut body = String::new();
-        response.body_mut().read_to_string(&mut body).await?;
-        anyhow::bail!(
-            "Failed to connect to Ollama API: {} {}",
-            response.status(),
-            body,
-        );
-    }
+        .body(AsyncBody::from(
+            serde_json::to_string(&request).map_err(anyhow::Error::from)?,
+        ))
+        .map_err(anyhow::Error::from)?;
+
+    let body = send_streaming(client, request).await?;
+    Ok(error_if_truncated(
+        parse_chat_stream(body, on_parse_error),
+        |delta: &ChatResponseDelta| delta.done,
+    ))
 }
 
-pub async fn get_models(
+/// Send a chat request without streaming and return the complete assistant message
+pub async fn chat_completion(
     client: &dyn HttpClient,
     api_url: &str,
     api_key: Option<&str>,
-) -> Result<Vec<LocalModelListing>> {
-    let uri = format!("{api_url}/api/tags");
+    request: ChatRequest,
+) -> Result<ChatMessage> {
+    let request = ChatRequest {
+        stream: false,
+        ..request
+    };
+    let uri = format!("{api_url}/api/chat");
     let request = HttpRequest::builder()
-        .method(Method::GET)
+        .method(Method::POST)
         .uri(uri)
-        .header("Accept", "application/json")
+        .header("Content-Type", "application/json")
         .when_some(api_key, |builder, api_key| {
             builder.header("Authorization", format!("Bearer {api_key}"))
         })
-        .body(AsyncBody::default())?;
+        .body(AsyncBody::from(
+            serde_json::to_string(&request).map_err(anyhow::Error::from)?,
+        ))
+        .map_err(anyhow::Error::from)?;
+
+    let body = send_and_read_body(client, request).await?;
+    let response: ChatResponseDelta = serde_json::from_str(&body)?;
+    if !response.done {
+        return Err(anyhow::anyhow!("Ollama returned an incomplete chat response").into());
+    }
+    Ok(response.message)
+}
 
-    let mut response = client.send(request).await?;
+/// What to do when a chat stream is cancelled before the response is done
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CancelBehavior {
+    /// Only stop reading the response
+    #[default]
+    DropOnly,
+    /// Also unload the model, so a shared machine gets its memory back right away
+    Unload,
+}
 
-    let mut body = String::new();
-    response.body_mut().read_to_string(&mut body).await?;
-
-    anyhow::ensure!(
-        response.status().is_success(),
-        "Failed to connect to Ollama API: {} {}",
-        response.status(),
-        body,
-    );
-    let response: LocalModelsResponse =
-        serde_json::from_str(&body).context("Unable to parse Ollama tag listing")?;
-    Ok(response.models)
+/// Unloads a model in the background when dropped, unless it was disarmed because the
+/// response finished
+struct UnloadOnDrop {
+    client: Arc<dyn HttpClient>,
+    api_url: String,
+    api_key: Option<String>,
+    model: String,
+    armed: bool,
 }
 
-/// Fetch details of a model, used to determine model capabilities
-pub async fn show_model(
-    client: &dyn HttpClient,
-    api_url: &str,
+impl Drop for UnloadOnDrop {
+    fn drop(&mut self) {
+        if !self.armed {
+            return;
+        }
+        let client = self.client.clone();
+        let api_url = std::mem::take(&mut self.api_url);
+        let api_key = self.api_key.take();
+        let model = std::mem::take(&mut self.model);
+        smol::spawn(async move {
+            if let Err(error) =
+                unload_model(client.as_ref(), &api_url, api_key.as_deref(), &model).await
+            {
+                log::warn!("Failed to unload Ollama model {model} after cancellation: {error}");
+            }
+        })
+        .detach();
+    }
+}
+
+/// Like [`stream_chat_completion`], but ends the stream with [`OllamaError::Cancelled`]
+/// as soon as `cancel` completes.
+///
+/// With [`CancelBehavior::Unload`], dropping the stream before the response is done also
+/// unloads the model, whether it was cancelled through `cancel` or simply dropped. The
+/// unload request outlives this call, which is why the client is taken by `Arc`.
+pub async fn stream_chat_completion_cancellable(
+    client: Arc<dyn HttpClient>,
+    api_url: &str,
+    api_key: Option<&str>,
+    request: ChatRequest,
+    cancel: impl Future<Output = ()> + Send + 'static,
+    cancel_behavior: CancelBehavior,
+) -> Result<BoxStream<'static, Result<ChatResponseDelta>>> {
+    let model = request.model.clone();
+    let stream = stream_chat_completion(client.as_ref(), api_url, api_key, request).await?;
+    let unload = (cancel_behavior == CancelBehavior::Unload).then(|| UnloadOnDrop {
+        client,
+        api_url: api_url.to_string(),
+        api_key: api_key.map(ToString::to_string),
+        model,
+        armed: true,
+    });
+    let cancel = cancel.boxed().fuse();
+
+    Ok(
+        futures::stream::unfold(Some((stream, cancel, unload)), |state| async move {
+            let (mut stream, mut cancel, mut unload) = state?;
+            futures::select! {
+                item = stream.next().fuse() => {
+                    let item = item?;
+                    if let Ok(delta) = &item
+                        && delta.done
+                        && let Some(unload) = &mut unload
+                    {
+                        unload.armed = false;
+                    }
+                    Some((item, Some((stream, cancel, unload))))
+                }
+                () = cancel => Some((Err(OllamaError::Cancelled), None)),
+            }
+        })
+        .boxed(),
+    )
+}
+
+pub async fn generate_completion(
+    client: &dyn HttpClient,
+    api_url: &str,
+    api_key: Option<&str>,
+    request: GenerateRequest,
+) -> Result<BoxStream<'static, Result<GenerateResponseDelta>>> {
+    let uri = format!("{api_url}/api/generate");
+    let request = HttpRequest::builder()
+        .method(Method::POST)
+        .uri(uri)
+        .header("Content-Type", "application/json")
+        .when_some(api_key, |builder, api_key| {
+            builder.header("Authorization", format!("Bearer {api_key}"))
+        })
+        .body(AsyncBody::from(
+            serde_json::to_string(&request).map_err(anyhow::Error::from)?,
+        ))
+        .map_err(anyhow::Error::from)?;
+
+    let body = send_streaming(client, request).await?;
+    Ok(error_if_truncated(
+        parse_ndjson_stream(body, OnParseError::default()),
+        |delta: &GenerateResponseDelta| delta.done,
+    ))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PullProgress {
+    pub status: String,
+    pub digest: Option<String>,
+    pub total: Option<u64>,
+    pub completed: Option<u64>,
+}
+
+/// Download a model from the Ollama library, reporting progress as it goes
+pub async fn pull_model(
+    client: &dyn HttpClient,
+    api_url: &str,
+    api_key: Option<&str>,
+    name: &str,
+) -> Result<BoxStream<'static, Result<PullProgress>>> {
+    let uri = format!("{api_url}/api/pull");
+    let request = HttpRequest::builder()
+        .method(Method::POST)
+        .uri(uri)
+        .header("Content-Type", "application/json")
+        .when_some(api_key, |builder, api_key| {
+            builder.header("Authorization", format!("Bearer {api_key}"))
+        })
+        .body(AsyncBody::from(
+            serde_json::json!({ "model": name, "stream": true }).to_string(),
+        ))
+        .map_err(anyhow::Error::from)?;
+
+    let body = send_streaming(client, request).await?;
+    Ok(parse_ndjson_stream(body, OnParseError::default()))
+}
+
+async fn send_streaming(
+    client: &dyn HttpClient,
+    request: HttpRequest<AsyncBody>,
+) -> Result<AsyncBody> {
+    let mut response = client
+        .send(request)
+        .await
+        .map_err(OllamaError::ServerUnavailable)?;
+    if response.status().is_success() {
+        Ok(response.into_body())
+    } else {
+        let mut body = String::new();
+        response
+            .body_mut()
+            .read_to_string(&mut body)
+            .await
+            .map_err(anyhow::Error::from)?;
+        Err(OllamaError::from_status(response.status(), body))
+    }
+}
+
+/// The body Ollama sends in place of a result when a request fails, sometimes with a
+/// successful status code or in the middle of a stream
+#[derive(Deserialize)]
+struct ErrorResponse {
+    error: String,
+}
+
+impl ErrorResponse {
+    fn parse(body: &str) -> Option<String> {
+        serde_json::from_str::<ErrorResponse>(body)
+            .ok()
+            .map(|response| response.error)
+    }
+}
+
+pub async fn get_models(
+    client: &dyn HttpClient,
+    api_url: &str,
+    api_key: Option<&str>,
+) -> Result<Vec<LocalModelListing>> {
+    let uri = format!("{api_url}/api/tags");
+    let request = HttpRequest::builder()
+        .method(Method::GET)
+        .uri(uri)
+        .header("Accept", "application/json")
+        .when_some(api_key, |builder, api_key| {
+            builder.header("Authorization", format!("Bearer {api_key}"))
+        })
+        .body(AsyncBody::default())
+        .map_err(anyhow::Error::from)?;
+
+    let body = send_and_read_body(client, request).await?;
+    let response: LocalModelsResponse = serde_json::from_str(&body)?;
+    Ok(response.models)
+}
+
+/// Like [`get_models`], but gives up with [`OllamaError::Cancelled`] as soon as `cancel`
+/// completes, e.g. when the model picker is dismissed while a slow server is responding
+pub async fn get_models_cancellable(
+    client: &dyn HttpClient,
+    api_url: &str,
+    api_key: Option<&str>,
+    cancel: impl Future<Output = ()>,
+) -> Result<Vec<LocalModelListing>> {
+    let models = get_models(client, api_url, api_key).fuse();
+    let cancel = cancel.fuse();
+    futures::pin_mut!(models, cancel);
+    futures::select! {
+        models = models => models,
+        () = cancel => Err(OllamaError::Cancelled),
+    }
+}
+
+/// List the models currently loaded into memory, e.g. to show what is using the GPU
+pub async fn running_models(
+    client: &dyn HttpClient,
+    api_url: &str,
+    api_key: Option<&str>,
+) -> Result<Vec<RunningModel>> {
+    let uri = format!("{api_url}/api/ps");
+    let request = HttpRequest::builder()
+        .method(Method::GET)
+        .uri(uri)
+        .header("Accept", "application/json")
+        .when_some(api_key, |builder, api_key| {
+            builder.header("Authorization", format!("Bearer {api_key}"))
+        })
+        .body(AsyncBody::default())
+        .map_err(anyhow::Error::from)?;
+
+    let body = send_and_read_body(client, request).await?;
+    let response: RunningModelsResponse = serde_json::from_str(&body)?;
+    Ok(response.models)
+}
+
+/// Fetch the version of the Ollama server, used to determine available features
+pub async fn get_version(
+    client: &dyn HttpClient,
+    api_url: &str,
+    api_key: Option<&str>,
+) -> Result<Version> {
+    let uri = format!("{api_url}/api/version");
+    let request = HttpRequest::builder()
+        .method(Method::GET)
+        .uri(uri)
+        .header("Accept", "application/json")
+        .when_some(api_key, |builder, api_key| {
+            builder.header("Authorization", format!("Bearer {api_key}"))
+        })
+        .body(AsyncBody::default())
+        .map_err(anyhow::Error::from)?;
+
+    let body = send_and_read_body(client, request).await?;
+    let response: VersionResponse = serde_json::from_str(&body)?;
+    let version = Version::parse(&response.version).map_err(anyhow::Error::from)?;
+    Ok(version)
+}
+
+/// How long [`ping`] waits for the server before treating it as unreachable
+const PING_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Checks whether a server is listening at `api_url`. Any HTTP response counts, even an
+/// error status, so this can tell "Ollama isn't running" apart from other failures.
+/// Unlike [`get_models`], an unreachable server is reported as `Ok(false)`.
+pub async fn ping(client: &dyn HttpClient, api_url: &str, api_key: Option<&str>) -> Result<bool> {
+    ping_with_timeout(client, api_url, api_key, PING_TIMEOUT).await
+}
+
+async fn ping_with_timeout(
+    client: &dyn HttpClient,
+    api_url: &str,
+    api_key: Option<&str>,
+    timeout: Duration,
+) -> Result<bool> {
+    let uri = format!("{api_url}/api/version");
+    let request = HttpRequest::builder()
+        .method(Method::GET)
+        .uri(uri)
+        .when_some(api_key, |builder, api_key| {
+            builder.header("Authorization", format!("Bearer {api_key}"))
+        })
+        .body(AsyncBody::default())
+        .map_err(anyhow::Error::from)?;
+
+    match futures::future::select(client.send(request), smol::Timer::after(timeout)).await {
+        Either::Left((response, _)) => Ok(response.is_ok()),
+        Either::Right(_) => Ok(false),
+    }
+}
+
+/// Fetch details of a model, used to determine model capabilities
+pub async fn show_model(
+    client: &dyn HttpClient,
+    api_url: &str,
     api_key: Option<&str>,
     model: &str,
 ) -> Result<ModelShow> {
@@ -362,25 +1632,261 @@ pub async fn show_model(
         })
         .body(AsyncBody::from(
             serde_json::json!({ "model": model }).to_string(),
-        ))?;
+        ))
+        .map_err(anyhow::Error::from)?;
 
-    let mut response = client.send(request).await?;
-    let mut body = String::new();
-    response.body_mut().read_to_string(&mut body).await?;
-
-    anyhow::ensure!(
-        response.status().is_success(),
-        "Failed to connect to Ollama API: {} {}",
-        response.status(),
-        body,
-    );
+    let body = send_and_read_body(client, request).await?;
     let details: ModelShow = serde_json::from_str(body.as_str())?;
     Ok(details)
 }
 
+/// Fetch details of a model and build a [`Model`] with its capabilities populated
+pub async fn load_model(
+    client: &dyn HttpClient,
+    api_url: &str,
+    api_key: Option<&str>,
+    name: &str,
+) -> Result<Model> {
+    let details = show_model(client, api_url, api_key, name).await?;
+    Ok(Model::from_show(name, &details))
+}
+
+/// Remembers the results of [`load_model`] for a while, so that listing models doesn't
+/// need an `/api/show` round-trip for each of them every time
+pub struct ModelCapabilityCache {
+    ttl: Duration,
+    models: Mutex<HashMap<(String, String), (Instant, Model)>>,
+}
+
+impl ModelCapabilityCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            models: Mutex::default(),
+        }
+    }
+
+    /// Like [`load_model`], but returns the cached model if it was fetched within the TTL
+    pub async fn load_model(
+        &self,
+        client: &dyn HttpClient,
+        api_url: &str,
+        api_key: Option<&str>,
+        name: &str,
+    ) -> Result<Model> {
+        let key = (api_url.to_string(), name.to_string());
+        {
+            let models = self.models.lock();
+            if let Some((fetched_at, model)) = models.get(&key)
+                && fetched_at.elapsed() < self.ttl
+            {
+                return Ok(model.clone());
+            }
+        }
+
+        let model = load_model(client, api_url, api_key, name).await?;
+        self.models
+            .lock()
+            .insert(key, (Instant::now(), model.clone()));
+        Ok(model)
+    }
+
+    /// Forgets a model, e.g. after it was pulled again or deleted
+    pub fn invalidate(&self, api_url: &str, name: &str) {
+        self.models
+            .lock()
+            .remove(&(api_url.to_string(), name.to_string()));
+    }
+
+    pub fn clear(&self) {
+        self.models.lock().clear();
+    }
+}
+
+/// Ask the server to evict a model from memory right away, instead of waiting for its
+/// `keep_alive` to run out
+pub async fn unload_model(
+    client: &dyn HttpClient,
+    api_url: &str,
+    api_key: Option<&str>,
+    name: &str,
+) -> Result<()> {
+    let uri = format!("{api_url}/api/chat");
+    let request = HttpRequest::builder()
+        .method(Method::POST)
+        .uri(uri)
+        .header("Content-Type", "application/json")
+        .when_some(api_key, |builder, api_key| {
+            builder.header("Authorization", format!("Bearer {api_key}"))
+        })
+        .body(AsyncBody::from(
+            serde_json::json!({
+                "model": name,
+                "messages": [],
+                "keep_alive": KeepAlive::unload(),
+            })
+            .to_string(),
+        ))
+        .map_err(anyhow::Error::from)?;
+
+    send_and_read_body(client, request).await?;
+    Ok(())
+}
+
+/// Remove a model and its data from the Ollama server
+pub async fn delete_model(
+    client: &dyn HttpClient,
+    api_url: &str,
+    api_key: Option<&str>,
+    name: &str,
+) -> Result<()> {
+    let uri = format!("{api_url}/api/delete");
+    let request = HttpRequest::builder()
+        .method(Method::DELETE)
+        .uri(uri)
+        .header("Content-Type", "application/json")
+        .when_some(api_key, |builder, api_key| {
+            builder.header("Authorization", format!("Bearer {api_key}"))
+        })
+        .body(AsyncBody::from(
+            serde_json::json!({ "model": name }).to_string(),
+        ))
+        .map_err(anyhow::Error::from)?;
+
+    send_and_read_body(client, request).await?;
+    Ok(())
+}
+
+/// Create a copy of a model under a new name
+pub async fn copy_model(
+    client: &dyn HttpClient,
+    api_url: &str,
+    api_key: Option<&str>,
+    source: &str,
+    destination: &str,
+) -> Result<()> {
+    let uri = format!("{api_url}/api/copy");
+    let request = HttpRequest::builder()
+        .method(Method::POST)
+        .uri(uri)
+        .header("Content-Type", "application/json")
+        .when_some(api_key, |builder, api_key| {
+            builder.header("Authorization", format!("Bearer {api_key}"))
+        })
+        .body(AsyncBody::from(
+            serde_json::json!({ "source": source, "destination": destination }).to_string(),
+        ))
+        .map_err(anyhow::Error::from)?;
+
+    send_and_read_body(client, request).await?;
+    Ok(())
+}
+
+async fn send_and_read_body(
+    client: &dyn HttpClient,
+    request: HttpRequest<AsyncBody>,
+) -> Result<String> {
+    let mut response = client
+        .send(request)
+        .await
+        .map_err(OllamaError::ServerUnavailable)?;
+
+    let mut body = String::new();
+    response
+        .body_mut()
+        .read_to_string(&mut body)
+        .await
+        .map_err(anyhow::Error::from)?;
+
+    if !response.status().is_success() {
+        Err(OllamaError::from_status(response.status(), body))
+    } else if let Some(error) = ErrorResponse::parse(&body) {
+        Err(OllamaError::Api(error))
+    } else {
+        Ok(body)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use futures::{TryStreamExt as _, executor::block_on};
+    use http_client::{FakeHttpClient, HttpClientWithUrl, Response};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    pub(crate) async fn read_body(request: HttpRequest<AsyncBody>) -> (Method, String, Value) {
+        let method = request.method().clone();
+        let uri = request.uri().to_string();
+        let mut body = String::new();
+        request.into_body().read_to_string(&mut body).await.unwrap();
+        (method, uri, serde_json::from_str(&body).unwrap())
+    }
+
+    #[test]
+    fn api_url_from_ollama_host() {
+        assert_eq!(api_url_from_host(None), OLLAMA_API_URL);
+        assert_eq!(api_url_from_host(Some("  ")), OLLAMA_API_URL);
+        assert_eq!(
+            api_url_from_host(Some("0.0.0.0:1234")),
+            "http://0.0.0.0:1234"
+        );
+        assert_eq!(
+            api_url_from_host(Some("http://box:11434")),
+            "http://box:11434"
+        );
+        assert_eq!(api_url_from_host(Some("box")), "http://box:11434");
+        assert_eq!(api_url_from_host(Some(":8080")), "http://127.0.0.1:8080");
+        assert_eq!(
+            api_url_from_host(Some("https://ollama.example.com/")),
+            "https://ollama.example.com:443"
+        );
+        assert_eq!(
+            api_url_from_host(Some("http://box/ollama")),
+            "http://box:80/ollama"
+        );
+        assert_eq!(api_url_from_host(Some("[::1]")), "http://[::1]:11434");
+    }
+
+    #[test]
+    fn local_api_urls() {
+        assert!(is_local_api_url(OLLAMA_API_URL));
+        assert!(is_local_api_url("http://localhost:1234"));
+        assert!(is_local_api_url("http://127.0.0.1:8080/"));
+        assert!(is_local_api_url("http://0.0.0.0:1234"));
+        assert!(is_local_api_url("http://[::1]:11434"));
+        assert!(is_local_api_url("localhost:11434"));
+        assert!(!is_local_api_url("http://box:11434"));
+        assert!(!is_local_api_url("https://ollama.example.com"));
+    }
+
+    #[test]
+    fn max_tokens_for_known_and_unknown_models() {
+        assert_eq!(get_max_tokens("phi:latest"), 2048);
+        assert_eq!(get_max_tokens("llama3.1:8b"), MAXIMUM_TOKENS);
+        assert_eq!(get_max_tokens_with_limit("llama3.1:8b", 200_000), 128000);
+        assert_eq!(get_max_tokens("not-a-known-model"), DEFAULT_TOKENS);
+        assert_eq!(get_max_tokens(""), DEFAULT_TOKENS);
+        assert_eq!(get_max_tokens(":"), DEFAULT_TOKENS);
+        assert_eq!(get_max_tokens_with_limit("llama3.1:8b", 0), 1);
+    }
+
+    #[test]
+    fn max_tokens_for_registered_prefix() {
+        assert_eq!(get_max_tokens("registered-test-model:7b"), DEFAULT_TOKENS);
+
+        register_model_context("registered-test-model", 8192);
+        register_model_context("registered-test-model:70b", 65536);
+
+        assert_eq!(get_max_tokens("registered-test-model:7b"), 8192);
+        assert_eq!(get_max_tokens("registered-test-model:70b"), MAXIMUM_TOKENS);
+        assert_eq!(
+            get_max_tokens_with_limit("registered-test-model:70b", 100_000),
+            65536
+        );
+
+        register_model_context("registered-test-model", 2048);
+        assert_eq!(get_max_tokens("registered-test-model:7b"), 2048);
+    }
 
     #[test]
     fn parse_completion() {
@@ -402,6 +1908,62 @@ mod tests {
         let _: ChatResponseDelta = serde_json::from_value(response).unwrap();
     }
 
+    #[test]
+    fn chat_completion_request() {
+        let client = FakeHttpClient::create(|request| async move {
+            let (method, uri, body) = read_body(request).await;
+            assert_eq!(method, Method::POST);
+            assert_eq!(uri, "http://localhost:11434/api/chat");
+            assert_eq!(body["stream"], false);
+            let response = serde_json::json!({
+                "model": "llama3.2",
+                "created_at": "2023-12-12T14:13:43.416799Z",
+                "message": {
+                    "role": "assistant",
+                    "content": "Hello! How are you today?"
+                },
+                "done_reason": "stop",
+                "done": true,
+                "prompt_eval_count": 26,
+                "eval_count": 298
+            });
+            Ok(Response::builder()
+                .status(200)
+                .body(AsyncBody::from(response.to_string()))?)
+        });
+
+        let message = block_on(chat_completion(
+            client.as_ref(),
+            OLLAMA_API_URL,
+            None,
+            ChatRequest {
+                model: "llama3.2".to_string(),
+                messages: vec![ChatMessage::User {
+                    content: "Hello".to_string(),
+                    images: None,
+                }],
+                stream: true,
+                keep_alive: KeepAlive::default(),
+                options: None,
+                think: None,
+                tools: vec![],
+            },
+        ))
+        .unwrap();
+
+        match message {
+            ChatMessage::Assistant {
+                content,
+                tool_calls,
+                ..
+            } => {
+                assert_eq!(content, "Hello! How are you today?");
+                assert!(tool_calls.is_none());
+            }
+            _ => panic!("Deserialized wrong role"),
+        }
+    }
+
     #[test]
     fn parse_streaming_completion() {
         let partial = serde_json::json!({
@@ -417,28 +1979,1276 @@ mod tests {
 
         let _: ChatResponseDelta = serde_json::from_value(partial).unwrap();
 
-        let last = serde_json::json!({
-        "model": "llama3.2",
-        "created_at": "2023-08-04T19:22:45.499127Z",
-        "message": {
-            "role": "assistant",
-            "content": ""
-        },
-        "done": true,
-        "total_duration": 4883583458u64,
-        "load_duration": 1334875,
-        "prompt_eval_count": 26,
-        "prompt_eval_duration": 342546000,
-        "eval_count": 282,
-        "eval_duration": 4535599000u64
-        });
+        let last = serde_json::json!({
+        "model": "llama3.2",
+        "created_at": "2023-08-04T19:22:45.499127Z",
+        "message": {
+            "role": "assistant",
+            "content": ""
+        },
+        "done": true,
+        "total_duration": 4883583458u64,
+        "load_duration": 1334875,
+        "prompt_eval_count": 26,
+        "prompt_eval_duration": 342546000,
+        "eval_count": 282,
+        "eval_duration": 4535599000u64
+        });
+
+        let _: ChatResponseDelta = serde_json::from_value(last).unwrap();
+    }
+
+    #[test]
+    fn parse_streaming_generate_response() {
+        let partial = serde_json::json!({
+            "model": "qwen2.5-coder:1.5b",
+            "created_at": "2025-06-02T10:12:03.245145Z",
+            "response": "    let",
+            "done": false
+        });
+        let partial: GenerateResponseDelta = serde_json::from_value(partial).unwrap();
+        assert_eq!(partial.response, "    let");
+        assert!(!partial.done);
+
+        let last = serde_json::json!({
+            "model": "qwen2.5-coder:1.5b",
+            "created_at": "2025-06-02T10:12:03.845145Z",
+            "response": "",
+            "done": true,
+            "done_reason": "stop",
+            "context": [1, 2, 3],
+            "total_duration": 612345000,
+            "load_duration": 1334875,
+            "prompt_eval_count": 31,
+            "prompt_eval_duration": 142546000,
+            "eval_count": 12,
+            "eval_duration": 435599000
+        });
+        let last: GenerateResponseDelta = serde_json::from_value(last).unwrap();
+        assert!(last.done);
+        assert_eq!(last.done_reason.as_deref(), Some("stop"));
+        assert_eq!(last.eval_count, Some(12));
+    }
+
+    #[test]
+    fn generate_context_round_trip() {
+        let response: GenerateResponseDelta = serde_json::from_value(serde_json::json!({
+            "model": "llama3.2",
+            "created_at": "2025-06-02T10:12:03.845145Z",
+            "response": "",
+            "done": true,
+            "context": [128006, 882, 128007, 271, 9906]
+        }))
+        .unwrap();
+        assert_eq!(response.context, Some(vec![128006, 882, 128007, 271, 9906]));
+
+        let request = serde_json::to_value(GenerateRequest {
+            model: "llama3.2".to_string(),
+            prompt: "And then?".to_string(),
+            suffix: None,
+            context: response.context,
+            options: None,
+            stream: false,
+        })
+        .unwrap();
+        assert_eq!(
+            request["context"],
+            serde_json::json!([128006, 882, 128007, 271, 9906])
+        );
+        assert!(request.get("suffix").is_none());
+
+        let request = serde_json::to_value(GenerateRequest {
+            model: "llama3.2".to_string(),
+            prompt: "Hello".to_string(),
+            suffix: None,
+            context: None,
+            options: None,
+            stream: false,
+        })
+        .unwrap();
+        assert!(request.get("context").is_none());
+    }
+
+    #[test]
+    fn generate_completion_request() {
+        let client = FakeHttpClient::create(|request| async move {
+            let (method, uri, body) = read_body(request).await;
+            assert_eq!(method, Method::POST);
+            assert_eq!(uri, "http://localhost:11434/api/generate");
+            assert_eq!(body["prompt"], "fn add(a: i32, b: i32) -> i32 {\n");
+            assert_eq!(body["suffix"], "\n}");
+            assert_eq!(body["stream"], true);
+
+            let body = [
+                r#"{"model":"qwen2.5-coder:1.5b","created_at":"2025-06-02T10:12:03.245145Z","response":"    a","done":false}"#,
+                r#"{"model":"qwen2.5-coder:1.5b","created_at":"2025-06-02T10:12:03.345145Z","response":" + b","done":false}"#,
+                r#"{"model":"qwen2.5-coder:1.5b","created_at":"2025-06-02T10:12:03.445145Z","response":"","done":true,"done_reason":"stop"}"#,
+            ]
+            .join("\n");
+            Ok(Response::builder()
+                .status(200)
+                .body(AsyncBody::from(body))?)
+        });
+
+        let deltas = block_on(async {
+            generate_completion(
+                client.as_ref(),
+                OLLAMA_API_URL,
+                None,
+                GenerateRequest {
+                    model: "qwen2.5-coder:1.5b".to_string(),
+                    prompt: "fn add(a: i32, b: i32) -> i32 {\n".to_string(),
+                    suffix: Some("\n}".to_string()),
+                    context: None,
+                    options: None,
+                    stream: true,
+                },
+            )
+            .await
+            .unwrap()
+            .collect::<Vec<_>>()
+            .await
+        })
+        .into_iter()
+        .collect::<Result<Vec<_>>>()
+        .unwrap();
+
+        let completion = deltas
+            .iter()
+            .map(|delta| delta.response.as_str())
+            .collect::<String>();
+        assert_eq!(completion, "    a + b");
+        assert!(deltas.last().is_some_and(|delta| delta.done));
+    }
+
+    #[test]
+    fn parse_tool_call() {
+        let response = serde_json::json!({
+            "model": "llama3.2:3b",
+            "created_at": "2025-04-28T20:02:02.140489Z",
+            "message": {
+                "role": "assistant",
+                "content": "",
+                "tool_calls": [
+                    {
+                        "id": "call_llama3.2:3b_145155",
+                        "function": {
+                            "name": "weather",
+                            "arguments": {
+                                "city": "london",
+                            }
+                        }
+                    }
+                ]
+            },
+            "done_reason": "stop",
+            "done": true,
+            "total_duration": 2758629166u64,
+            "load_duration": 1770059875,
+            "prompt_eval_count": 147,
+            "prompt_eval_duration": 684637583,
+            "eval_count": 16,
+            "eval_duration": 302561917,
+        });
+
+        let result: ChatResponseDelta = serde_json::from_value(response).unwrap();
+        match result.message {
+            ChatMessage::Assistant {
+                content,
+                tool_calls,
+                images: _,
+                thinking,
+            } => {
+                assert!(content.is_empty());
+                assert!(tool_calls.is_some_and(|v| !v.is_empty()));
+                assert!(thinking.is_none());
+            }
+            _ => panic!("Deserialized wrong role"),
+        }
+    }
+
+    // Backwards compatibility with Ollama versions prior to v0.12.10 November 2025
+    // This test is a copy of `parse_tool_call()` with the `id` field omitted.
+    #[test]
+    fn parse_tool_call_pre_0_12_10() {
+        let response = serde_json::json!({
+            "model": "llama3.2:3b",
+            "created_at": "2025-04-28T20:02:02.140489Z",
+            "message": {
+                "role": "assistant",
+                "content": "",
+                "tool_calls": [
+                    {
+                        "function": {
+                            "name": "weather",
+                            "arguments": {
+                                "city": "london",
+                            }
+                        }
+                    }
+                ]
+            },
+            "done_reason": "stop",
+            "done": true,
+            "total_duration": 2758629166u64,
+            "load_duration": 1770059875,
+            "prompt_eval_count": 147,
+            "prompt_eval_duration": 684637583,
+            "eval_count": 16,
+            "eval_duration": 302561917,
+        });
+
+        let result: ChatResponseDelta = serde_json::from_value(response).unwrap();
+        match result.message {
+            ChatMessage::Assistant {
+                content,
+                tool_calls: Some(tool_calls),
+                images: _,
+                thinking,
+            } => {
+                assert!(content.is_empty());
+                assert!(thinking.is_none());
+
+                // When the `Option` around `id` is removed, this test should complain
+                // and be subsequently deleted in favor of `parse_tool_call()`
+                assert!(tool_calls.first().is_some_and(|call| call.id.is_none()))
+            }
+            _ => panic!("Deserialized wrong role"),
+        }
+    }
+
+    fn show_model_response() -> Value {
+        serde_json::json!({
+            "license": "LLAMA 3.2 COMMUNITY LICENSE AGREEMENT...",
+            "details": {
+                "parent_model": "",
+                "format": "gguf",
+                "family": "llama",
+                "families": ["llama"],
+                "parameter_size": "3.2B",
+                "quantization_level": "Q4_K_M"
+            },
+            "model_info": {
+                "general.architecture": "llama",
+                "general.basename": "Llama-3.2",
+                "general.file_type": 15,
+                "general.finetune": "Instruct",
+                "general.languages": ["en", "de", "fr", "it", "pt", "hi", "es", "th"],
+                "general.parameter_count": 3212749888u64,
+                "general.quantization_version": 2,
+                "general.size_label": "3B",
+                "general.tags": ["facebook", "meta", "pytorch", "llama", "llama-3", "text-generation"],
+                "general.type": "model",
+                "llama.attention.head_count": 24,
+                "llama.attention.head_count_kv": 8,
+                "llama.attention.key_length": 128,
+                "llama.attention.layer_norm_rms_epsilon": 0.00001,
+                "llama.attention.value_length": 128,
+                "llama.block_count": 28,
+                "llama.context_length": 131072,
+                "llama.embedding_length": 3072,
+                "llama.feed_forward_length": 8192,
+                "llama.rope.dimension_count": 128,
+                "llama.rope.freq_base": 500000,
+                "llama.vocab_size": 128256,
+                "tokenizer.ggml.bos_token_id": 128000,
+                "tokenizer.ggml.eos_token_id": 128009,
+                "tokenizer.ggml.merges": null,
+                "tokenizer.ggml.model": "gpt2",
+                "tokenizer.ggml.pre": "llama-bpe",
+                "tokenizer.ggml.token_type": null,
+                "tokenizer.ggml.tokens": null
+            },
+            "tensors": [
+                { "name": "rope_freqs.weight", "type": "F32", "shape": [64] },
+                { "name": "token_embd.weight", "type": "Q4_K_S", "shape": [3072, 128256] }
+            ],
+            "capabilities": ["completion", "tools"],
+            "modified_at": "2025-04-29T21:24:41.445877632+03:00"
+        })
+    }
+
+    #[test]
+    fn parse_pull_progress() {
+        let body = [
+            r#"{"status":"pulling manifest"}"#,
+            r#"{"status":"pulling 6a0746a1ec1a","digest":"sha256:6a0746a1ec1a","total":2019377376,"completed":241970}"#,
+            r#"{"status":"pulling 6a0746a1ec1a","digest":"sha256:6a0746a1ec1a","total":2019377376,"completed":1009884800}"#,
+            r#"{"status":"pulling 6a0746a1ec1a","digest":"sha256:6a0746a1ec1a","total":2019377376,"completed":2019377376}"#,
+            r#"{"status":"verifying sha256 digest"}"#,
+            r#"{"status":"writing manifest"}"#,
+            r#"{"status":"success"}"#,
+        ]
+        .join("\n");
+
+        let progress = block_on(
+            parse_ndjson_stream::<PullProgress, _>(
+                futures::io::Cursor::new(body),
+                OnParseError::default(),
+            )
+            .collect::<Vec<_>>(),
+        )
+        .into_iter()
+        .collect::<Result<Vec<_>>>()
+        .unwrap();
+
+        assert_eq!(progress.len(), 7);
+        let completed = progress
+            .iter()
+            .filter_map(|progress| progress.completed)
+            .collect::<Vec<_>>();
+        assert_eq!(completed.len(), 3);
+        assert!(completed.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(progress.last().unwrap().status, "success");
+    }
+
+    #[test]
+    fn pull_model_request() {
+        let client = FakeHttpClient::create(|request| async move {
+            assert_eq!(
+                request.headers()["Authorization"].to_str().unwrap(),
+                "Bearer secret"
+            );
+            assert_eq!(
+                request.headers()["Content-Type"].to_str().unwrap(),
+                "application/json"
+            );
+            let (method, uri, body) = read_body(request).await;
+            assert_eq!(method, Method::POST);
+            assert_eq!(uri, "http://localhost:11434/api/pull");
+            assert_eq!(
+                body,
+                serde_json::json!({ "model": "llama3.2:latest", "stream": true })
+            );
+            Ok(Response::builder().status(200).body(AsyncBody::from(
+                "{\"status\":\"pulling manifest\"}\n{\"status\":\"success\"}\n",
+            ))?)
+        });
+
+        let progress = block_on(async {
+            pull_model(
+                client.as_ref(),
+                OLLAMA_API_URL,
+                Some("secret"),
+                "llama3.2:latest",
+            )
+            .await
+            .unwrap()
+            .try_collect::<Vec<_>>()
+            .await
+        })
+        .unwrap();
+        assert_eq!(
+            progress
+                .iter()
+                .map(|progress| progress.status.as_str())
+                .collect::<Vec<_>>(),
+            ["pulling manifest", "success"]
+        );
+    }
+
+    pub(crate) fn delta_content(delta: &ChatResponseDelta) -> &str {
+        match &delta.message {
+            ChatMessage::Assistant { content, .. } => content,
+            _ => panic!("expected an assistant message"),
+        }
+    }
+
+    /// A stand-in for an Ollama server that serves canned responses for the chat, tags
+    /// and show endpoints, checking that each request is well formed.
+    pub(crate) fn fake_ollama_server() -> Arc<HttpClientWithUrl> {
+        FakeHttpClient::create(|request| async move {
+            assert_eq!(
+                request.headers()["Authorization"].to_str().unwrap(),
+                "Bearer secret"
+            );
+            let path = request.uri().path().to_string();
+            let method = request.method().clone();
+            let response = match (method, path.as_str()) {
+                (Method::GET, "/api/tags") => serde_json::json!({
+                    "models": [{
+                        "name": "llama3.2:latest",
+                        "modified_at": "2025-04-29T21:24:41.445877632+03:00",
+                        "size": 2019393189u64,
+                        "digest": "a80c4f17acd55265feec403c7aef86be0c25983ab279d83f3bcd3abbcb5b8b72",
+                        "details": {
+                            "format": "gguf",
+                            "family": "llama",
+                            "families": ["llama"],
+                            "parameter_size": "3.2B",
+                            "quantization_level": "Q4_K_M"
+                        }
+                    }]
+                })
+                .to_string(),
+                (Method::POST, "/api/show") => {
+                    let (_, _, body) = read_body(request).await;
+                    assert_eq!(body, serde_json::json!({ "model": "llama3.2:latest" }));
+                    show_model_response().to_string()
+                }
+                (Method::POST, "/api/chat") => {
+                    assert_eq!(
+                        request.headers()["Content-Type"].to_str().unwrap(),
+                        "application/json"
+                    );
+                    let (_, _, body) = read_body(request).await;
+                    assert_eq!(body["model"], "llama3.2:latest");
+                    assert_eq!(body["stream"], true);
+                    assert_eq!(body["messages"][0]["role"], "user");
+                    assert_eq!(body["messages"][0]["content"], "Hi");
+                    [
+                        r#"{"model":"llama3.2:latest","created_at":"2025-04-29T21:24:41Z","message":{"role":"assistant","content":"Hello"},"done":false}"#,
+                        r#"{"model":"llama3.2:latest","created_at":"2025-04-29T21:24:42Z","message":{"role":"assistant","content":"!"},"done":false}"#,
+                        r#"{"model":"llama3.2:latest","created_at":"2025-04-29T21:24:43Z","message":{"role":"assistant","content":""},"done_reason":"stop","done":true,"prompt_eval_count":26,"eval_count":2}"#,
+                    ]
+                    .join("\n")
+                }
+                (method, path) => panic!("unexpected request: {method} {path}"),
+            };
+            Ok(Response::builder()
+                .status(200)
+                .body(AsyncBody::from(response))?)
+        })
+    }
+
+    fn listing(name: &str, family: &str, modified_at: &str, size: u64) -> LocalModelListing {
+        LocalModelListing {
+            name: name.to_string(),
+            modified_at: modified_at.to_string(),
+            size,
+            digest: String::new(),
+            details: ModelDetails {
+                format: "gguf".to_string(),
+                family: family.to_string(),
+                families: None,
+                parameter_size: String::new(),
+                quantization_level: String::new(),
+            },
+        }
+    }
+
+    #[test]
+    fn listing_size_human() {
+        assert_eq!(listing("a", "llama", "", 512).size_human(), "512 B");
+        assert_eq!(listing("a", "llama", "", 1_500).size_human(), "1.5 KB");
+        assert_eq!(
+            listing("a", "llama", "", 2_019_393_189).size_human(),
+            "2.0 GB"
+        );
+        assert_eq!(
+            listing("a", "llama", "", 41_000_000_000_000).size_human(),
+            "41.0 TB"
+        );
+    }
+
+    #[test]
+    fn group_listings_by_family() {
+        let families = group_by_family([
+            // Lexicographically first, but an hour later once the offsets are applied
+            listing("llama3.2:1b", "llama", "2025-04-29T21:24:41+03:00", 1),
+            listing("llama3.2:3b", "llama", "2025-04-29T19:30:00+01:00", 1),
+            listing("qwen3:8b", "qwen3", "2025-05-01T08:00:00Z", 1),
+            listing("llama3.1:8b", "llama", "not a date", 1),
+            listing(
+                "llama3:8b",
+                "llama",
+                "2024-07-01T12:00:00.123456789-07:00",
+                1,
+            ),
+        ]);
+
+        assert_eq!(
+            families.keys().map(String::as_str).collect::<Vec<_>>(),
+            ["llama", "qwen3"]
+        );
+        assert_eq!(
+            families["llama"]
+                .iter()
+                .map(|model| model.name.as_str())
+                .collect::<Vec<_>>(),
+            ["llama3.2:3b", "llama3.2:1b", "llama3:8b", "llama3.1:8b"]
+        );
+        assert_eq!(families["qwen3"].len(), 1);
+    }
+
+    #[test]
+    fn running_models_end_to_end() {
+        let client = FakeHttpClient::create(|request| async move {
+            assert_eq!(request.uri().path(), "/api/ps");
+            let response = serde_json::json!({
+                "models": [
+                    {
+                        "name": "llama3.2:latest",
+                        "model": "llama3.2:latest",
+                        "size": 3850000000u64,
+                        "digest": "a80c4f17acd55265feec403c7aef86be0c25983ab279d83f3bcd3abbcb5b8b72",
+                        "details": {
+                            "format": "gguf",
+                            "family": "llama",
+                            "families": ["llama"],
+                            "parameter_size": "3.2B",
+                            "quantization_level": "Q4_K_M"
+                        },
+                        "expires_at": "2025-04-29T21:29:41.445877632+03:00",
+                        "size_vram": 3850000000u64
+                    },
+                    {
+                        "name": "nomic-embed-text:latest",
+                        "model": "nomic-embed-text:latest",
+                        "size": 580000000u64,
+                        "digest": "0a109f422b47e3a30ba2b10eca18548e944e8a23073ee3f3e947efcf3c45e59f",
+                        "details": {
+                            "format": "gguf",
+                            "family": "nomic-bert",
+                            "families": ["nomic-bert"],
+                            "parameter_size": "137M",
+                            "quantization_level": "F16"
+                        },
+                        "expires_at": "2025-04-29T18:25:00Z",
+                        "size_vram": 0
+                    }
+                ]
+            });
+            Ok(Response::builder()
+                .status(200)
+                .body(AsyncBody::from(response.to_string()))?)
+        });
+
+        let models = block_on(running_models(client.as_ref(), OLLAMA_API_URL, None)).unwrap();
+        assert_eq!(models.len(), 2);
+        assert_eq!(models[0].name, "llama3.2:latest");
+        assert_eq!(models[0].size, 3850000000);
+        assert_eq!(models[0].size_vram, 3850000000);
+        assert_eq!(
+            models[0].expires_at,
+            DateTime::parse_from_rfc3339("2025-04-29T21:29:41.445877632+03:00").unwrap()
+        );
+        assert_eq!(models[1].name, "nomic-embed-text:latest");
+        assert_eq!(models[1].size_vram, 0);
+        assert_eq!(models[1].expires_at.timestamp(), 1745951100);
+    }
+
+    #[test]
+    fn get_models_end_to_end() {
+        let client = fake_ollama_server();
+        let models = block_on(get_models(client.as_ref(), OLLAMA_API_URL, Some("secret"))).unwrap();
+        assert_eq!(models.len(), 1);
+        assert_eq!(models[0].name, "llama3.2:latest");
+        assert_eq!(models[0].details.parameter_size, "3.2B");
+    }
+
+    #[test]
+    fn ping_reports_reachability() {
+        let client = FakeHttpClient::create(|request| async move {
+            assert_eq!(request.uri().path(), "/api/version");
+            Ok(Response::builder()
+                .status(200)
+                .body(AsyncBody::from(r#"{"version":"0.12.10"}"#))?)
+        });
+        assert!(block_on(ping(client.as_ref(), OLLAMA_API_URL, None)).unwrap());
+
+        // A server that doesn't know the endpoint is still up
+        let client = FakeHttpClient::create(|_request| async move {
+            Ok(Response::builder()
+                .status(404)
+                .body(AsyncBody::from("404 page not found"))?)
+        });
+        assert!(block_on(ping(client.as_ref(), OLLAMA_API_URL, None)).unwrap());
+
+        let client = FakeHttpClient::create(|_request| async move {
+            Err(anyhow::anyhow!("Connection refused (os error 111)"))
+        });
+        assert!(!block_on(ping(client.as_ref(), OLLAMA_API_URL, None)).unwrap());
+
+        let client = FakeHttpClient::create(|_request| async move {
+            futures::future::pending::<()>().await;
+            Ok(Response::builder().status(200).body(AsyncBody::default())?)
+        });
+        assert!(
+            !block_on(ping_with_timeout(
+                client.as_ref(),
+                OLLAMA_API_URL,
+                None,
+                Duration::from_millis(10),
+            ))
+            .unwrap()
+        );
+    }
+
+    #[test]
+    fn get_models_cancelled_while_waiting() {
+        let client = FakeHttpClient::create(|_request| async move {
+            // A server that never finishes responding
+            futures::future::pending::<()>().await;
+            Ok(Response::builder().status(200).body(AsyncBody::default())?)
+        });
+
+        let result = block_on(get_models_cancellable(
+            client.as_ref(),
+            OLLAMA_API_URL,
+            None,
+            futures::future::ready(()),
+        ));
+        assert!(matches!(result, Err(OllamaError::Cancelled)));
+
+        let client = fake_ollama_server();
+        let models = block_on(get_models_cancellable(
+            client.as_ref(),
+            OLLAMA_API_URL,
+            Some("secret"),
+            futures::future::pending(),
+        ))
+        .unwrap();
+        assert_eq!(models.len(), 1);
+    }
+
+    #[test]
+    fn show_model_end_to_end() {
+        let client = fake_ollama_server();
+        let details = block_on(show_model(
+            client.as_ref(),
+            OLLAMA_API_URL,
+            Some("secret"),
+            "llama3.2:latest",
+        ))
+        .unwrap();
+        assert!(details.supports_tools());
+        assert_eq!(details.context_length, Some(131072));
+    }
+
+    #[test]
+    fn stream_chat_completion_end_to_end() {
+        let client = fake_ollama_server();
+        let deltas = block_on(async {
+            stream_chat_completion(
+                client.as_ref(),
+                OLLAMA_API_URL,
+                Some("secret"),
+                ChatRequest {
+                    model: "llama3.2:latest".to_string(),
+                    messages: vec![ChatMessage::User {
+                        content: "Hi".to_string(),
+                        images: None,
+                    }],
+                    stream: true,
+                    keep_alive: KeepAlive::default(),
+                    options: None,
+                    think: None,
+                    tools: vec![],
+                },
+            )
+            .await
+            .unwrap()
+            .collect::<Vec<_>>()
+            .await
+        })
+        .into_iter()
+        .collect::<Result<Vec<_>>>()
+        .unwrap();
+
+        assert_eq!(
+            deltas.iter().map(delta_content).collect::<String>(),
+            "Hello!"
+        );
+        let last = deltas.last().unwrap();
+        assert!(last.done);
+        assert_eq!(last.eval_count, Some(2));
+    }
+
+    fn counting_show_server() -> (Arc<HttpClientWithUrl>, Arc<AtomicUsize>) {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let client = FakeHttpClient::create({
+            let requests = requests.clone();
+            move |_request| {
+                requests.fetch_add(1, Ordering::SeqCst);
+                async move {
+                    Ok(Response::builder()
+                        .status(200)
+                        .body(AsyncBody::from(show_model_response().to_string()))?)
+                }
+            }
+        });
+        (client, requests)
+    }
+
+    #[test]
+    fn model_capability_cache_reuses_fresh_entries() {
+        let (client, requests) = counting_show_server();
+        let cache = ModelCapabilityCache::new(Duration::from_secs(60));
+
+        let model =
+            block_on(cache.load_model(client.as_ref(), OLLAMA_API_URL, None, "llama3.2")).unwrap();
+        assert_eq!(model.supports_tools, Some(true));
+        let cached =
+            block_on(cache.load_model(client.as_ref(), OLLAMA_API_URL, None, "llama3.2")).unwrap();
+        assert_eq!(cached, model);
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+        // Entries are per server and per model
+        block_on(cache.load_model(client.as_ref(), "http://remote:11434", None, "llama3.2"))
+            .unwrap();
+        block_on(cache.load_model(client.as_ref(), OLLAMA_API_URL, None, "qwen3")).unwrap();
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+
+        cache.invalidate(OLLAMA_API_URL, "llama3.2");
+        block_on(cache.load_model(client.as_ref(), OLLAMA_API_URL, None, "llama3.2")).unwrap();
+        assert_eq!(requests.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn model_capability_cache_refetches_expired_entries() {
+        let (client, requests) = counting_show_server();
+        let cache = ModelCapabilityCache::new(Duration::ZERO);
+
+        block_on(cache.load_model(client.as_ref(), OLLAMA_API_URL, None, "llama3.2")).unwrap();
+        block_on(cache.load_model(client.as_ref(), OLLAMA_API_URL, None, "llama3.2")).unwrap();
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn show_model_error_in_successful_response() {
+        let client = FakeHttpClient::create(|_request| async move {
+            Ok(Response::builder()
+                .status(200)
+                .body(AsyncBody::from(r#"{"error":"model 'foo' not found"}"#))?)
+        });
+
+        let result = block_on(show_model(client.as_ref(), OLLAMA_API_URL, None, "foo"));
+        assert!(matches!(
+            result,
+            Err(OllamaError::Api(error)) if error == "model 'foo' not found"
+        ));
+    }
+
+    #[test]
+    fn stream_chat_completion_truncated() {
+        let client = FakeHttpClient::create(|_request| async move {
+            let body = [
+                r#"{"model":"llama3.2","created_at":"2025-04-29T21:24:41Z","message":{"role":"assistant","content":"Hello"},"done":false}"#,
+                r#"{"model":"llama3.2","created_at":"2025-04-29T21:24:42Z","message":{"role":"assistant","content":" wor"},"done":false}"#,
+            ]
+            .join("\n");
+            Ok(Response::builder()
+                .status(200)
+                .body(AsyncBody::from(body))?)
+        });
+
+        let items = block_on(async {
+            stream_chat_completion(
+                client.as_ref(),
+                OLLAMA_API_URL,
+                None,
+                ChatRequest {
+                    model: "llama3.2".to_string(),
+                    messages: vec![ChatMessage::User {
+                        content: "Hi".to_string(),
+                        images: None,
+                    }],
+                    stream: true,
+                    keep_alive: KeepAlive::default(),
+                    options: None,
+                    think: None,
+                    tools: vec![],
+                },
+            )
+            .await
+            .unwrap()
+            .collect::<Vec<_>>()
+            .await
+        });
+
+        assert_eq!(items.len(), 3);
+        assert!(items[..2].iter().all(Result::is_ok));
+        assert!(matches!(items[2], Err(OllamaError::Truncated)));
+    }
+
+    #[test]
+    fn truncated_after_error_line() {
+        let body = [
+            r#"{"model":"llama3.2","created_at":"2025-04-29T21:24:41Z","message":{"role":"assistant","content":"Hello"},"done":false}"#,
+            r#"{"error":"an error was encountered while running the model"}"#,
+        ]
+        .join("\n");
+
+        let items = block_on(
+            error_if_truncated(
+                parse_chat_stream(futures::io::Cursor::new(body), OnParseError::Skip),
+                |delta: &ChatResponseDelta| delta.done,
+            )
+            .collect::<Vec<_>>(),
+        );
+        assert_eq!(items.len(), 3);
+        assert!(items[0].is_ok());
+        assert!(matches!(items[1], Err(OllamaError::Api(_))));
+        assert!(matches!(items[2], Err(OllamaError::Truncated)));
+
+        // A line that is too long stops the stream, so it isn't truncated on top of that
+        let items = block_on(
+            error_if_truncated(
+                parse_ndjson_stream_with_max_line::<ChatResponseDelta, _>(
+                    futures::io::Cursor::new("x".repeat(64)),
+                    OnParseError::Fail,
+                    16,
+                ),
+                |delta: &ChatResponseDelta| delta.done,
+            )
+            .collect::<Vec<_>>(),
+        );
+        assert_eq!(items.len(), 1);
+        assert!(matches!(items[0], Err(OllamaError::LineTooLong(16))));
+    }
+
+    #[test]
+    fn unload_model_request() {
+        let client = FakeHttpClient::create(|request| async move {
+            let (method, uri, body) = read_body(request).await;
+            assert_eq!(method, Method::POST);
+            assert_eq!(uri, "http://localhost:11434/api/chat");
+            assert_eq!(
+                body,
+                serde_json::json!({ "model": "llama3.2:latest", "messages": [], "keep_alive": 0 })
+            );
+            let response = serde_json::json!({
+                "model": "llama3.2:latest",
+                "created_at": "2025-04-29T21:24:41Z",
+                "message": { "role": "assistant", "content": "" },
+                "done_reason": "unload",
+                "done": true
+            });
+            Ok(Response::builder()
+                .status(200)
+                .body(AsyncBody::from(response.to_string()))?)
+        });
+
+        block_on(unload_model(
+            client.as_ref(),
+            OLLAMA_API_URL,
+            None,
+            "llama3.2:latest",
+        ))
+        .unwrap();
+    }
+
+    /// A server that streams one delta of a response that never finishes, and reports
+    /// the bodies of unload requests on the returned channel
+    fn never_finishing_server() -> (
+        Arc<dyn HttpClient>,
+        futures::channel::mpsc::UnboundedReceiver<Value>,
+    ) {
+        let (unload_tx, unload_rx) = futures::channel::mpsc::unbounded();
+        let client = FakeHttpClient::create({
+            move |request| {
+                let unload_tx = unload_tx.clone();
+                async move {
+                    let (_, _, body) = read_body(request).await;
+                    if body["stream"] == true {
+                        // One delta, then a response that never finishes
+                        let line = r#"{"model":"llama3.2","created_at":"2025-04-29T21:24:41Z","message":{"role":"assistant","content":"Hello"},"done":false}"#;
+                        let chunks = futures::stream::iter([Ok(format!("{line}\n").into_bytes())])
+                            .chain(futures::stream::pending());
+                        Ok(Response::builder()
+                            .status(200)
+                            .body(AsyncBody::from_reader(chunks.into_async_read()))?)
+                    } else {
+                        let response = serde_json::json!({
+                            "model": "llama3.2",
+                            "created_at": "2025-04-29T21:24:42Z",
+                            "message": { "role": "assistant", "content": "" },
+                            "done_reason": "unload",
+                            "done": true
+                        });
+                        unload_tx.unbounded_send(body).ok();
+                        Ok(Response::builder()
+                            .status(200)
+                            .body(AsyncBody::from(response.to_string()))?)
+                    }
+                }
+            }
+        });
+        (client, unload_rx)
+    }
+
+    fn cancellable_stream(
+        client: Arc<dyn HttpClient>,
+        cancel: impl Future<Output = ()> + Send + 'static,
+    ) -> BoxStream<'static, Result<ChatResponseDelta>> {
+        let request = ChatRequest {
+            model: "llama3.2".to_string(),
+            messages: vec![ChatMessage::User {
+                content: "Hi".to_string(),
+                images: None,
+            }],
+            stream: true,
+            keep_alive: KeepAlive::default(),
+            options: None,
+            think: None,
+            tools: vec![],
+        };
+        block_on(stream_chat_completion_cancellable(
+            client,
+            OLLAMA_API_URL,
+            None,
+            request,
+            cancel,
+            CancelBehavior::Unload,
+        ))
+        .unwrap()
+    }
+
+    fn expect_unload(unload_rx: &mut futures::channel::mpsc::UnboundedReceiver<Value>) {
+        let unload = block_on(futures::future::select(
+            unload_rx.next(),
+            smol::Timer::after(Duration::from_secs(5)),
+        ));
+        let Either::Left((Some(body), _)) = unload else {
+            panic!("the model was not unloaded");
+        };
+        assert_eq!(
+            body,
+            serde_json::json!({ "model": "llama3.2", "messages": [], "keep_alive": 0 })
+        );
+    }
+
+    #[test]
+    fn stream_chat_completion_cancel_unloads_model() {
+        let (client, mut unload_rx) = never_finishing_server();
+        let (cancel_tx, cancel_rx) = futures::channel::oneshot::channel::<()>();
+        let mut stream = cancellable_stream(client, async move {
+            cancel_rx.await.ok();
+        });
+
+        block_on(async {
+            assert!(stream.next().await.unwrap().is_ok());
+            cancel_tx.send(()).unwrap();
+            assert!(matches!(
+                stream.next().await,
+                Some(Err(OllamaError::Cancelled))
+            ));
+            assert!(stream.next().await.is_none());
+        });
+        expect_unload(&mut unload_rx);
+    }
+
+    #[test]
+    fn stream_chat_completion_drop_unloads_model() {
+        let (client, mut unload_rx) = never_finishing_server();
+        let mut stream = cancellable_stream(client, futures::future::pending());
+
+        assert!(block_on(stream.next()).unwrap().is_ok());
+        drop(stream);
+        expect_unload(&mut unload_rx);
+    }
+
+    #[test]
+    fn delete_model_request() {
+        let client = FakeHttpClient::create(|request| async move {
+            assert_eq!(
+                request.headers()["Authorization"].to_str().unwrap(),
+                "Bearer secret"
+            );
+            let (method, uri, body) = read_body(request).await;
+            assert_eq!(method, Method::DELETE);
+            assert_eq!(uri, "http://localhost:11434/api/delete");
+            assert_eq!(body, serde_json::json!({ "model": "llama3.2:latest" }));
+            Ok(Response::builder().status(200).body(AsyncBody::default())?)
+        });
+
+        block_on(delete_model(
+            client.as_ref(),
+            OLLAMA_API_URL,
+            Some("secret"),
+            "llama3.2:latest",
+        ))
+        .unwrap();
+    }
+
+    #[test]
+    fn copy_model_request() {
+        let client = FakeHttpClient::create(|request| async move {
+            assert!(!request.headers().contains_key("Authorization"));
+            let (method, uri, body) = read_body(request).await;
+            assert_eq!(method, Method::POST);
+            assert_eq!(uri, "http://localhost:11434/api/copy");
+            assert_eq!(
+                body,
+                serde_json::json!({ "source": "llama3.2", "destination": "llama3.2-backup" })
+            );
+            Ok(Response::builder()
+                .status(404)
+                .body(AsyncBody::from(r#"{"error":"model 'llama3.2' not found"}"#))?)
+        });
+
+        let result = block_on(copy_model(
+            client.as_ref(),
+            OLLAMA_API_URL,
+            None,
+            "llama3.2",
+            "llama3.2-backup",
+        ));
+        assert!(matches!(result, Err(OllamaError::ModelNotFound(_))));
+    }
+
+    pub(crate) fn assistant_delta(content: &str, thinking: Option<&str>) -> ChatResponseDelta {
+        ChatResponseDelta {
+            model: "llama3.2".to_string(),
+            created_at: "2023-08-04T08:52:19.385406455-07:00".to_string(),
+            message: ChatMessage::Assistant {
+                content: content.to_string(),
+                tool_calls: None,
+                images: None,
+                thinking: thinking.map(ToString::to_string),
+            },
+            done_reason: None,
+            done: false,
+            prompt_eval_count: None,
+            eval_count: None,
+            skipped_lines: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn parse_tool_call_arguments() {
+        #[derive(Deserialize, Debug, PartialEq)]
+        struct WeatherInput {
+            city: String,
+        }
+
+        let tool_call = |arguments: Value| OllamaToolCall {
+            id: None,
+            function: OllamaFunctionCall {
+                name: "weather".to_string(),
+                arguments,
+            },
+        };
+
+        let input: WeatherInput = tool_call(serde_json::json!({ "city": "london" }))
+            .parse_arguments()
+            .unwrap();
+        assert_eq!(
+            input,
+            WeatherInput {
+                city: "london".to_string()
+            }
+        );
+
+        let error = tool_call(serde_json::json!({ "town": "london" }))
+            .parse_arguments::<WeatherInput>()
+            .unwrap_err();
+        assert!(matches!(
+            &error,
+            OllamaError::InvalidToolArguments { name, .. } if name == "weather"
+        ));
+        assert!(
+            error.to_string().contains("missing field `city`"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn idle_timeout_fires_when_stream_stalls() {
+        let delta = |content: &str| -> Result<ChatResponseDelta> {
+            Ok(ChatResponseDelta {
+                model: "llama3.2".to_string(),
+                created_at: "2025-04-29T21:24:41Z".to_string(),
+                message: ChatMessage::Assistant {
+                    content: content.to_string(),
+                    tool_calls: None,
+                    images: None,
+                    thinking: None,
+                },
+                done_reason: None,
+                done: false,
+                prompt_eval_count: None,
+                eval_count: None,
+                skipped_lines: Vec::new(),
+            })
+        };
+        // Two deltas, then the connection stays open without sending anything
+        let stalled = futures::stream::iter([delta("Hello"), delta(" wor")])
+            .chain(futures::stream::pending())
+            .boxed();
+
+        let items =
+            block_on(with_idle_timeout(stalled, Duration::from_millis(50)).collect::<Vec<_>>());
+        assert_eq!(items.len(), 3);
+        assert_eq!(delta_content(items[0].as_ref().unwrap()), "Hello");
+        assert_eq!(delta_content(items[1].as_ref().unwrap()), " wor");
+        assert!(matches!(
+            items[2],
+            Err(OllamaError::IdleTimeout(timeout)) if timeout == Duration::from_millis(50)
+        ));
+    }
+
+    #[test]
+    fn with_throughput_keeps_deltas() {
+        let mut done = assistant_delta("", None);
+        done.done = true;
+        let deltas = vec![
+            Ok(assistant_delta("Hello", None)),
+            Ok(assistant_delta(" world", None)),
+            Ok(assistant_delta("!", None)),
+            Ok(done),
+        ];
+        // The deltas arrive 100ms, 50ms and 50ms apart
+        let start = Instant::now();
+        let mut arrivals = [0, 100, 150, 200]
+            .map(|millis| start + Duration::from_millis(millis))
+            .into_iter();
+        let results = block_on(
+            with_throughput_clock(futures::stream::iter(deltas).boxed(), move || {
+                arrivals.next().unwrap()
+            })
+            .collect::<Vec<_>>(),
+        )
+        .into_iter()
+        .collect::<Result<Vec<_>>>()
+        .unwrap();
+
+        assert_eq!(
+            results
+                .iter()
+                .map(|(delta, _)| delta_content(delta))
+                .collect::<Vec<_>>(),
+            ["Hello", " world", "!", ""]
+        );
+        let rates = results.iter().map(|(_, rate)| *rate).collect::<Vec<_>>();
+        // 10 tokens/s, then smoothed towards 20 tokens/s; the final delta carries no token
+        let expected = [0.0, 10.0, 13.0, 13.0];
+        for (rate, expected) in rates.iter().zip(expected) {
+            assert!((rate - expected).abs() < 0.01, "rates were {rates:?}");
+        }
+    }
+
+    fn collect_items(deltas: Vec<Value>) -> Vec<StreamItem> {
+        let deltas = deltas
+            .into_iter()
+            .map(|delta| Ok(serde_json::from_value(delta).unwrap()))
+            .collect::<Vec<_>>();
+        block_on(into_items(futures::stream::iter(deltas).boxed()).collect::<Vec<_>>())
+            .into_iter()
+            .collect::<Result<Vec<_>>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn stream_items_thinking_before_content() {
+        let items = collect_items(vec![
+            serde_json::json!({
+                "model": "qwen3",
+                "created_at": "2025-05-29T09:35:56.836222Z",
+                "message": { "role": "assistant", "content": "", "thinking": "Hmm" },
+                "done": false
+            }),
+            serde_json::json!({
+                "model": "qwen3",
+                "created_at": "2025-05-29T09:35:56.936222Z",
+                "message": { "role": "assistant", "content": "Hello" },
+                "done": false
+            }),
+            serde_json::json!({
+                "model": "qwen3",
+                "created_at": "2025-05-29T09:35:57.036222Z",
+                "message": { "role": "assistant", "content": "" },
+                "done_reason": "stop",
+                "done": true,
+                "prompt_eval_count": 12,
+                "eval_count": 34
+            }),
+        ]);
+
+        assert_eq!(items.len(), 3);
+        assert!(matches!(&items[0], StreamItem::Thinking(text) if text == "Hmm"));
+        assert!(matches!(&items[1], StreamItem::Content(text) if text == "Hello"));
+        assert!(matches!(
+            &items[2],
+            StreamItem::Done(CompletionStats {
+                prompt_eval_count: Some(12),
+                eval_count: Some(34),
+                done_reason: Some(DoneReason::Stop),
+                skipped_lines,
+            }) if skipped_lines.is_empty()
+        ));
+    }
+
+    #[test]
+    fn stream_items_done_reason() {
+        let done = |done_reason: &str| {
+            let items = collect_items(vec![serde_json::json!({
+                "model": "llama3.2",
+                "created_at": "2025-05-29T09:35:57.036222Z",
+                "message": { "role": "assistant", "content": "" },
+                "done_reason": done_reason,
+                "done": true
+            })]);
+            match items.as_slice() {
+                [StreamItem::Done(stats)] => stats.done_reason.clone(),
+                _ => panic!("expected a single done item"),
+            }
+        };
+
+        assert_eq!(done("stop"), Some(DoneReason::Stop));
+        assert_eq!(done("length"), Some(DoneReason::Length));
+        assert_eq!(done("tool_calls"), Some(DoneReason::ToolCalls));
+        assert_eq!(done("load"), Some(DoneReason::Other("load".to_string())));
+    }
 
-        let _: ChatResponseDelta = serde_json::from_value(last).unwrap();
+    #[test]
+    fn stream_content_to_writer() {
+        let deltas = [
+            serde_json::json!({
+                "model": "qwen3",
+                "created_at": "2025-05-29T09:35:56.836222Z",
+                "message": { "role": "assistant", "content": "", "thinking": "Hmm" },
+                "done": false
+            }),
+            serde_json::json!({
+                "model": "qwen3",
+                "created_at": "2025-05-29T09:35:56.936222Z",
+                "message": { "role": "assistant", "content": "Hello" },
+                "done": false
+            }),
+            serde_json::json!({
+                "model": "qwen3",
+                "created_at": "2025-05-29T09:35:57.036222Z",
+                "message": { "role": "assistant", "content": ", world" },
+                "done": false
+            }),
+            serde_json::json!({
+                "model": "qwen3",
+                "created_at": "2025-05-29T09:35:57.136222Z",
+                "message": { "role": "assistant", "content": "" },
+                "done_reason": "stop",
+                "done": true,
+                "prompt_eval_count": 12,
+                "eval_count": 3
+            }),
+        ]
+        .into_iter()
+        .map(|delta| Ok(serde_json::from_value(delta).unwrap()))
+        .collect::<Vec<_>>();
+
+        let mut output = Vec::new();
+        let stats = block_on(stream_to_writer(
+            futures::stream::iter(deltas).boxed(),
+            &mut output,
+        ))
+        .unwrap();
+
+        assert_eq!(String::from_utf8(output).unwrap(), "Hello, world");
+        assert_eq!(
+            stats,
+            CompletionStats {
+                prompt_eval_count: Some(12),
+                eval_count: Some(3),
+                done_reason: Some(DoneReason::Stop),
+                skipped_lines: Vec::new(),
+            }
+        );
     }
 
     #[test]
-    fn parse_tool_call() {
-        let response = serde_json::json!({
+    fn stream_items_tool_call_only() {
+        let items = collect_items(vec![serde_json::json!({
             "model": "llama3.2:3b",
             "created_at": "2025-04-28T20:02:02.140489Z",
             "message": {
@@ -447,149 +3257,327 @@ mod tests {
                 "tool_calls": [
                     {
                         "id": "call_llama3.2:3b_145155",
-                        "function": {
-                            "name": "weather",
-                            "arguments": {
-                                "city": "london",
-                            }
-                        }
+                        "function": { "name": "weather", "arguments": { "city": "london" } }
                     }
                 ]
             },
             "done_reason": "stop",
             "done": true,
-            "total_duration": 2758629166u64,
-            "load_duration": 1770059875,
             "prompt_eval_count": 147,
-            "prompt_eval_duration": 684637583,
-            "eval_count": 16,
-            "eval_duration": 302561917,
-        });
+            "eval_count": 16
+        })]);
+
+        assert_eq!(items.len(), 2);
+        assert!(matches!(
+            &items[0],
+            StreamItem::ToolCall(call) if call.function.name == "weather"
+        ));
+        assert!(matches!(&items[1], StreamItem::Done(_)));
+    }
 
-        let result: ChatResponseDelta = serde_json::from_value(response).unwrap();
-        match result.message {
+    #[test]
+    fn parse_show_model() {
+        let response = show_model_response();
+
+        let result: ModelShow = serde_json::from_value(response).unwrap();
+        assert!(result.supports_tools());
+        assert!(result.capabilities.contains(&"tools".to_string()));
+        assert!(result.capabilities.contains(&"completion".to_string()));
+
+        assert_eq!(result.architecture, Some("llama".to_string()));
+        assert_eq!(result.context_length, Some(131072));
+    }
+
+    #[test]
+    fn model_from_show() {
+        let details: ModelShow = serde_json::from_value(show_model_response()).unwrap();
+        let model = Model::from_show("llama3.2:latest", &details);
+
+        assert_eq!(model.name, "llama3.2:latest");
+        assert_eq!(model.display_name(), "llama3.2");
+        assert_eq!(model.max_tokens, 131072);
+        assert_eq!(model.supports_tools, Some(true));
+        assert_eq!(model.supports_vision, Some(false));
+        assert_eq!(model.supports_thinking, Some(false));
+
+        let details = ModelShow {
+            capabilities: vec!["completion".to_string(), "vision".to_string()],
+            context_length: None,
+            architecture: None,
+        };
+        let model = Model::from_show("llava:7b", &details);
+        assert_eq!(model.max_tokens, get_max_tokens("llava:7b"));
+        assert_eq!(model.supports_vision, Some(true));
+    }
+
+    #[test]
+    fn humanized_display_name() {
+        let humanized =
+            |name: &str| Model::new(name, None, None, None, None, None).humanized_display_name();
+        assert_eq!(humanized("llama3"), "Llama3");
+        assert_eq!(humanized("llama3:latest"), "Llama3");
+        assert_eq!(humanized("qwen2.5-coder:7b"), "Qwen2.5 Coder 7B");
+        assert_eq!(humanized("qwen2.5-coder:latest"), "Qwen2.5 Coder");
+        assert_eq!(humanized("qwen2.5:0.5b"), "Qwen2.5 0.5B");
+        assert_eq!(humanized("mixtral:8x7b"), "Mixtral 8x7B");
+        assert_eq!(humanized("smollm:135m"), "Smollm 135M");
+        assert_eq!(
+            humanized("llama3.1:8b-instruct-q4_K_M"),
+            "Llama3.1 8B instruct q4_K_M"
+        );
+        assert_eq!(humanized("deepseek-r1:e2b"), "Deepseek R1 e2b");
+
+        let model = Model::new("qwen2.5-coder:7b", Some("My Coder"), None, None, None, None);
+        assert_eq!(model.humanized_display_name(), "My Coder");
+        assert_eq!(model.name, "qwen2.5-coder:7b");
+    }
+
+    #[test]
+    fn estimate_tokens() {
+        let model = Model::new("llama3.2:latest", None, None, None, None, None);
+        let user = |content: &str| ChatMessage::User {
+            content: content.to_string(),
+            images: None,
+        };
+
+        assert_eq!(model.estimate_tokens(&[]), 0);
+        let short = model.estimate_tokens(&[user("Hello")]);
+        let long = model.estimate_tokens(&[user(&"Hello".repeat(100))]);
+        assert!(short < long);
+        assert_eq!(long, model.estimate_tokens(&[user(&"Hello".repeat(100))]));
+        assert!(long < model.estimate_tokens(&[user(&"Hello".repeat(100)), user("More")]));
+
+        let with_image = model.estimate_tokens(&[ChatMessage::user_with_images(
+            "Hello",
+            vec![b"image bytes".to_vec()],
+        )]);
+        assert!(with_image > short);
+
+        let assistant = |thinking: Option<&str>, tool_calls: Option<Vec<OllamaToolCall>>| {
             ChatMessage::Assistant {
-                content,
+                content: "Sure".to_string(),
                 tool_calls,
-                images: _,
-                thinking,
-            } => {
-                assert!(content.is_empty());
-                assert!(tool_calls.is_some_and(|v| !v.is_empty()));
-                assert!(thinking.is_none());
+                images: None,
+                thinking: thinking.map(ToString::to_string),
             }
-            _ => panic!("Deserialized wrong role"),
+        };
+        let plain = model.estimate_tokens(&[assistant(None, None)]);
+        assert!(plain < model.estimate_tokens(&[assistant(Some("Let me think about it"), None)]));
+        let tool_call = OllamaToolCall {
+            id: None,
+            function: OllamaFunctionCall {
+                name: "weather".to_string(),
+                arguments: serde_json::json!({ "city": "london" }),
+            },
+        };
+        assert!(plain < model.estimate_tokens(&[assistant(None, Some(vec![tool_call]))]));
+    }
+
+    #[test]
+    fn features_from_version() {
+        let response: VersionResponse =
+            serde_json::from_value(serde_json::json!({ "version": "0.12.10" })).unwrap();
+        let version = Version::parse(&response.version).unwrap();
+        let features = OllamaFeatures::from_version(&version);
+        assert!(features.tool_call_ids);
+        assert!(features.thinking);
+        assert!(features.structured_outputs);
+
+        let features = OllamaFeatures::from_version(&Version::parse("0.12.10-rc1").unwrap());
+        assert!(features.tool_call_ids);
+
+        let features = OllamaFeatures::from_version(&Version::new(0, 12, 9));
+        assert!(!features.tool_call_ids);
+        assert!(features.thinking);
+
+        let features = OllamaFeatures::from_version(&Version::new(0, 4, 7));
+        assert_eq!(
+            features,
+            OllamaFeatures {
+                tool_call_ids: false,
+                thinking: false,
+                structured_outputs: false,
+            }
+        );
+    }
+
+    #[test]
+    fn serialize_chat_options() {
+        let options = serde_json::to_value(ChatOptions::default()).unwrap();
+        for key in ["num_gpu", "seed", "repeat_penalty", "repeat_last_n"] {
+            assert!(options.get(key).is_none(), "{key} should be omitted");
         }
+
+        let options = serde_json::to_value(ChatOptions {
+            num_gpu: Some(32),
+            seed: Some(42),
+            repeat_penalty: Some(1.5),
+            repeat_last_n: Some(-1),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(options["num_gpu"], 32);
+        assert_eq!(options["seed"], 42);
+        assert_eq!(options["repeat_penalty"], 1.5);
+        assert_eq!(options["repeat_last_n"], -1);
     }
 
-    // Backwards compatibility with Ollama versions prior to v0.12.10 November 2025
-    // This test is a copy of `parse_tool_call()` with the `id` field omitted.
     #[test]
-    fn parse_tool_call_pre_0_12_10() {
-        let response = serde_json::json!({
-            "model": "llama3.2:3b",
-            "created_at": "2025-04-28T20:02:02.140489Z",
-            "message": {
-                "role": "assistant",
-                "content": "",
-                "tool_calls": [
-                    {
-                        "function": {
-                            "name": "weather",
-                            "arguments": {
-                                "city": "london",
-                            }
-                        }
-                    }
-                ]
-            },
-            "done_reason": "stop",
-            "done": true,
-            "total_duration": 2758629166u64,
-            "load_duration": 1770059875,
-            "prompt_eval_count": 147,
-            "prompt_eval_duration": 684637583,
-            "eval_count": 16,
-            "eval_duration": 302561917,
-        });
+    fn clamp_predict_to_context_budget() {
+        let model = Model::new("llama3.2:latest", None, Some(8192), None, None, None);
+        let clamp = |num_predict: Option<isize>, prompt_tokens: u64| {
+            let mut options = ChatOptions {
+                num_predict,
+                ..Default::default()
+            };
+            options.clamp_predict(&model, prompt_tokens);
+            options.num_predict
+        };
 
-        let result: ChatResponseDelta = serde_json::from_value(response).unwrap();
-        match result.message {
-            ChatMessage::Assistant {
-                content,
-                tool_calls: Some(tool_calls),
-                images: _,
-                thinking,
-            } => {
-                assert!(content.is_empty());
-                assert!(thinking.is_none());
+        assert_eq!(clamp(Some(512), 1000), Some(512));
+        assert_eq!(clamp(Some(512), 8000), Some(192));
+        assert_eq!(clamp(Some(512), 9000), Some(0));
+        assert_eq!(clamp(Some(-1), 8000), Some(192));
+        assert_eq!(clamp(Some(-2), 0), Some(8192));
+        assert_eq!(clamp(None, 4096), Some(4096));
+    }
 
-                // When the `Option` around `id` is removed, this test should complain
-                // and be subsequently deleted in favor of `parse_tool_call()`
-                assert!(tool_calls.first().is_some_and(|call| call.id.is_none()))
-            }
-            _ => panic!("Deserialized wrong role"),
+    #[test]
+    fn serialize_stop_sequences() {
+        let options = ChatOptions::default().with_stop(["\n\n", "END"]);
+        let serialized = serde_json::to_string(&options).unwrap();
+        assert!(
+            serialized.contains(r#""stop":["\n\n","END"]"#),
+            "{serialized}"
+        );
+
+        for options in [
+            ChatOptions::default(),
+            ChatOptions::default().with_stop(Vec::<String>::new()),
+        ] {
+            let serialized = serde_json::to_value(&options).unwrap();
+            assert!(serialized.get("stop").is_none(), "{serialized}");
         }
     }
 
     #[test]
-    fn parse_show_model() {
-        let response = serde_json::json!({
-            "license": "LLAMA 3.2 COMMUNITY LICENSE AGREEMENT...",
-            "details": {
-                "parent_model": "",
-                "format": "gguf",
-                "family": "llama",
-                "families": ["llama"],
-                "parameter_size": "3.2B",
-                "quantization_level": "Q4_K_M"
-            },
-            "model_info": {
-                "general.architecture": "llama",
-                "general.basename": "Llama-3.2",
-                "general.file_type": 15,
-                "general.finetune": "Instruct",
-                "general.languages": ["en", "de", "fr", "it", "pt", "hi", "es", "th"],
-                "general.parameter_count": 3212749888u64,
-                "general.quantization_version": 2,
-                "general.size_label": "3B",
-                "general.tags": ["facebook", "meta", "pytorch", "llama", "llama-3", "text-generation"],
-                "general.type": "model",
-                "llama.attention.head_count": 24,
-                "llama.attention.head_count_kv": 8,
-                "llama.attention.key_length": 128,
-                "llama.attention.layer_norm_rms_epsilon": 0.00001,
-                "llama.attention.value_length": 128,
-                "llama.block_count": 28,
-                "llama.context_length": 131072,
-                "llama.embedding_length": 3072,
-                "llama.feed_forward_length": 8192,
-                "llama.rope.dimension_count": 128,
-                "llama.rope.freq_base": 500000,
-                "llama.vocab_size": 128256,
-                "tokenizer.ggml.bos_token_id": 128000,
-                "tokenizer.ggml.eos_token_id": 128009,
-                "tokenizer.ggml.merges": null,
-                "tokenizer.ggml.model": "gpt2",
-                "tokenizer.ggml.pre": "llama-bpe",
-                "tokenizer.ggml.token_type": null,
-                "tokenizer.ggml.tokens": null
+    fn chat_request_for_model_defaults() {
+        let mut model = Model::new("llama3.2", None, Some(8192), None, None, None);
+        model.keep_alive = Some(KeepAlive::Duration("10m".to_string()));
+        let messages = || {
+            vec![ChatMessage::User {
+                content: "Hi".to_string(),
+                images: None,
+            }]
+        };
+
+        let request = ChatRequest::for_model(&model, messages(), ChatOptions::default());
+        assert_eq!(request.model, "llama3.2");
+        assert!(request.stream);
+        assert_eq!(request.keep_alive, KeepAlive::Duration("10m".to_string()));
+        let options = request.options.unwrap();
+        assert_eq!(options.num_ctx, Some(8192));
+        assert_eq!(options.temperature, None);
+
+        let request = ChatRequest::for_model(
+            &model,
+            messages(),
+            ChatOptions {
+                num_ctx: Some(2048),
+                temperature: Some(0.2),
+                ..ChatOptions::default()
             },
-            "tensors": [
-                { "name": "rope_freqs.weight", "type": "F32", "shape": [64] },
-                { "name": "token_embd.weight", "type": "Q4_K_S", "shape": [3072, 128256] }
-            ],
-            "capabilities": ["completion", "tools"],
-            "modified_at": "2025-04-29T21:24:41.445877632+03:00"
-        });
+        );
+        let options = request.options.unwrap();
+        assert_eq!(options.num_ctx, Some(2048));
+        assert_eq!(options.temperature, Some(0.2));
+
+        let request = ChatRequest::for_model(&model, messages(), ChatOptions::default())
+            .with_keep_alive(KeepAlive::unload());
+        assert_eq!(request.keep_alive, KeepAlive::unload());
+        assert_eq!(serde_json::to_value(&request).unwrap()["keep_alive"], 0);
+
+        model.keep_alive = None;
+        let request = ChatRequest::for_model(&model, messages(), ChatOptions::default());
+        assert_eq!(request.keep_alive, KeepAlive::default());
+    }
 
-        let result: ModelShow = serde_json::from_value(response).unwrap();
-        assert!(result.supports_tools());
-        assert!(result.capabilities.contains(&"tools".to_string()));
-        assert!(result.capabilities.contains(&"completion".to_string()));
+    #[test]
+    fn validate_images_before_sending() {
+        const TINY_PNG: &str = "iVBORw0KGgoAAAANSUhEUgAAAAEAAAABCAYAAAAfFcSJAAAADUlEQVR42mNk+M9QDwADhgGAWjR9awAAAABJRU5ErkJggg==";
+        let vision_model = Model::new("llava", None, None, None, Some(true), None);
+        let text_model = Model::new("llama3.2", None, None, None, Some(false), None);
+        let with_images = |images: &[&str]| {
+            vec![
+                ChatMessage::System {
+                    content: "Describe images.".to_string(),
+                },
+                ChatMessage::User {
+                    content: "What's this?".to_string(),
+                    images: Some(images.iter().map(|image| image.to_string()).collect()),
+                },
+            ]
+        };
 
-        assert_eq!(result.architecture, Some("llama".to_string()));
-        assert_eq!(result.context_length, Some(131072));
+        validate_images(&vision_model, &with_images(&[TINY_PNG]), MAX_IMAGE_BYTES).unwrap();
+        // Messages without images are fine for any model
+        validate_images(&text_model, &with_images(&[]), MAX_IMAGE_BYTES).unwrap();
+
+        let garbage = base64::engine::general_purpose::STANDARD.encode("not an image");
+        let truncated_png = base64::engine::general_purpose::STANDARD.encode(
+            &base64::engine::general_purpose::STANDARD
+                .decode(TINY_PNG)
+                .unwrap()[..40],
+        );
+        let error = validate_images(
+            &vision_model,
+            &with_images(&[TINY_PNG, &garbage, &truncated_png, "%%%"]),
+            MAX_IMAGE_BYTES,
+        )
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Invalid image: image 1 of message 1 isn't a complete PNG or JPEG; \
+             image 2 of message 1 isn't a complete PNG or JPEG; \
+             image 3 of message 1 isn't valid base64"
+        );
+
+        let error = validate_images(&vision_model, &with_images(&[TINY_PNG]), 64).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Invalid image: image 0 of message 1 is 70 bytes, over the 64 byte limit"
+        );
+
+        let error =
+            validate_images(&text_model, &with_images(&[TINY_PNG]), MAX_IMAGE_BYTES).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Invalid image: llama3.2 doesn't support images"
+        );
+    }
+
+    #[test]
+    fn enable_thinking_per_model() {
+        let request = || ChatRequest {
+            model: "qwen3".to_string(),
+            messages: Vec::new(),
+            stream: true,
+            keep_alive: KeepAlive::default(),
+            options: None,
+            think: None,
+            tools: vec![],
+        };
+        let thinking_model = Model::new("qwen3", None, None, None, None, Some(true));
+        let other_model = Model::new("llama3.2", None, None, None, None, Some(false));
+        let unknown_model = Model::new("mystery", None, None, None, None, None);
+
+        assert_eq!(request().enable_thinking(&thinking_model).think, Some(true));
+        assert_eq!(
+            request().with_think(false, &thinking_model).think,
+            Some(false)
+        );
+        assert_eq!(request().enable_thinking(&other_model).think, None);
+        assert_eq!(request().enable_thinking(&unknown_model).think, None);
     }
 
     #[test]
@@ -614,6 +3602,179 @@ mod tests {
         assert!(serialized.contains(base64_image));
     }
 
+    #[test]
+    fn assistant_message_images_round_trip() {
+        let base64_image = "iVBORw0KGgoAAAANSUhEUgAAAAEAAAABCAYAAAAfFcSJAAAADUlEQVR42mNk+M9QDwADhgGAWjR9awAAAABJRU5ErkJggg==";
+
+        let message = ChatMessage::Assistant {
+            content: "Here is the chart you asked for.".to_string(),
+            tool_calls: None,
+            images: Some(vec![base64_image.to_string()]),
+            thinking: None,
+        };
+        let serialized = serde_json::to_value(&message).unwrap();
+        assert_eq!(serialized["role"], "assistant");
+        assert_eq!(serialized["images"], serde_json::json!([base64_image]));
+
+        let parsed: ChatMessage = serde_json::from_value(serialized.clone()).unwrap();
+        match &parsed {
+            ChatMessage::Assistant {
+                content, images, ..
+            } => {
+                assert_eq!(content, "Here is the chart you asked for.");
+                assert_eq!(images.as_deref(), Some(&[base64_image.to_string()][..]));
+            }
+            _ => panic!("Expected assistant message"),
+        }
+        assert_eq!(serde_json::to_value(&parsed).unwrap(), serialized);
+    }
+
+    #[test]
+    fn validate_message_order() {
+        let system = |content: &str| ChatMessage::System {
+            content: content.to_string(),
+        };
+        let user = || ChatMessage::User {
+            content: "What's the weather in London and Paris?".to_string(),
+            images: None,
+        };
+        let tool_call = |city: &str| OllamaToolCall {
+            id: None,
+            function: OllamaFunctionCall {
+                name: "weather".to_string(),
+                arguments: serde_json::json!({ "city": city }),
+            },
+        };
+        let assistant = |tool_calls: Option<Vec<OllamaToolCall>>| ChatMessage::Assistant {
+            content: String::new(),
+            tool_calls,
+            images: None,
+            thinking: None,
+        };
+        let tool_result = |tool_name: &str| ChatMessage::Tool {
+            tool_name: tool_name.to_string(),
+            content: "Sunny".to_string(),
+            tool_call_id: None,
+        };
+
+        validate_messages(&[]).unwrap();
+        validate_messages(&[
+            system("You are a helpful assistant."),
+            system("Answer briefly."),
+            user(),
+            assistant(Some(vec![tool_call("London"), tool_call("Paris")])),
+            tool_result("weather"),
+            tool_result("weather"),
+            assistant(None),
+        ])
+        .unwrap();
+
+        let orphan = validate_messages(&[system("Be brief."), user(), tool_result("weather")]);
+        assert!(matches!(orphan, Err(OllamaError::InvalidMessages(_))));
+
+        let wrong_tool = validate_messages(&[
+            user(),
+            assistant(Some(vec![tool_call("London")])),
+            tool_result("time"),
+        ]);
+        assert!(matches!(wrong_tool, Err(OllamaError::InvalidMessages(_))));
+
+        let answered_twice = validate_messages(&[
+            user(),
+            assistant(Some(vec![tool_call("London")])),
+            tool_result("weather"),
+            tool_result("weather"),
+        ]);
+        assert!(matches!(
+            answered_twice,
+            Err(OllamaError::InvalidMessages(_))
+        ));
+
+        // Calls of the same tool are told apart by their ids
+        let tool_call_with_id = |id: &str, city: &str| OllamaToolCall {
+            id: Some(id.to_string()),
+            ..tool_call(city)
+        };
+        let tool_result_with_id = |id: &str| ChatMessage::Tool {
+            tool_name: "weather".to_string(),
+            content: "Sunny".to_string(),
+            tool_call_id: Some(id.to_string()),
+        };
+        let calls = || {
+            assistant(Some(vec![
+                tool_call_with_id("london", "London"),
+                tool_call_with_id("paris", "Paris"),
+            ]))
+        };
+        validate_messages(&[
+            user(),
+            calls(),
+            tool_result_with_id("paris"),
+            tool_result_with_id("london"),
+        ])
+        .unwrap();
+
+        let answered_twice_by_id = validate_messages(&[
+            user(),
+            calls(),
+            tool_result_with_id("paris"),
+            tool_result_with_id("paris"),
+        ]);
+        assert!(matches!(
+            answered_twice_by_id,
+            Err(OllamaError::InvalidMessages(_))
+        ));
+
+        let unknown_id = validate_messages(&[user(), calls(), tool_result_with_id("berlin")]);
+        assert!(matches!(unknown_id, Err(OllamaError::InvalidMessages(_))));
+    }
+
+    #[test]
+    fn user_message_with_images() {
+        let ChatMessage::User { images, .. } =
+            ChatMessage::user_with_images("What is this?", vec![b"image bytes".to_vec()])
+        else {
+            panic!("expected a user message");
+        };
+        assert_eq!(images, Some(vec!["aW1hZ2UgYnl0ZXM=".to_string()]));
+
+        let ChatMessage::User { images, .. } =
+            ChatMessage::user_with_images("No images", Vec::new())
+        else {
+            panic!("expected a user message");
+        };
+        assert_eq!(images, None);
+    }
+
+    #[test]
+    fn user_message_with_encoded_images() {
+        let base64_image = "iVBORw0KGgoAAAANSUhEUgAAAAEAAAABCAYAAAAfFcSJAAAADUlEQVR42mNk+M9QDwADhgGAWjR9awAAAABJRU5ErkJggg==";
+
+        let ChatMessage::User { images, .. } = ChatMessage::user_with_encoded_images(
+            "What do you see?",
+            vec![
+                format!("data:image/png;base64,{base64_image}"),
+                base64_image.to_string(),
+            ],
+        )
+        .unwrap() else {
+            panic!("expected a user message");
+        };
+        assert_eq!(
+            images,
+            Some(vec![base64_image.to_string(), base64_image.to_string()])
+        );
+
+        assert!(matches!(
+            ChatMessage::user_with_encoded_images("Garbage", vec!["not base64!".to_string()]),
+            Err(OllamaError::InvalidImage(_))
+        ));
+        assert!(matches!(
+            normalize_base64_image("data:image/png,rawbytes"),
+            Err(OllamaError::InvalidImage(_))
+        ));
+    }
+
     #[test]
     fn serialize_chat_request_without_images() {
         let request = ChatRequest {
@@ -657,4 +3818,25 @@ mod tests {
         assert_eq!(message_images.len(), 1);
         assert_eq!(message_images[0].as_str().unwrap(), base64_image);
     }
+
+    #[test]
+    fn error_from_status() {
+        let body = r#"{"error":"model 'foo' not found"}"#.to_string();
+        assert!(matches!(
+            OllamaError::from_status(StatusCode::NOT_FOUND, body.clone()),
+            OllamaError::ModelNotFound(message) if message == body
+        ));
+        assert!(matches!(
+            OllamaError::from_status(StatusCode::UNAUTHORIZED, String::new()),
+            OllamaError::Unauthorized(_)
+        ));
+        assert!(matches!(
+            OllamaError::from_status(StatusCode::FORBIDDEN, String::new()),
+            OllamaError::Unauthorized(_)
+        ));
+        assert!(matches!(
+            OllamaError::from_status(StatusCode::INTERNAL_SERVER_ERROR, "boom".to_string()),
+            OllamaError::Http { status: StatusCode::INTERNAL_SERVER_ERROR, body } if body == "boom"
+        ));
+    }
 }
@@ -0,0 +1,598 @@
+//! Chat completions through the OpenAI-compatible `/v1/chat/completions` endpoint, which
+//! Ollama serves alongside its own API, as do many other local model servers
+
+use std::collections::BTreeMap;
+
+use base64::Engine as _;
+use futures::{StreamExt as _, stream::BoxStream};
+use http_client::{AsyncBody, HttpClient, HttpRequestExt as _, Method, Request as HttpRequest};
+use serde::Deserialize;
+use serde_json::{Value, json};
+
+use crate::{
+    ChatMessage, ChatRequest, CompletionStats, DoneReason, OllamaError, OllamaFunctionCall,
+    OllamaToolCall, Result, StreamItem, send_streaming,
+    stream::{error_if_truncated, parse_sse_stream},
+};
+
+/// Streams a chat completion from an OpenAI-compatible server at `api_url`, producing the
+/// same [`StreamItem`]s as [`crate::into_items`] does for Ollama's own API
+pub async fn stream_openai_chat(
+    client: &dyn HttpClient,
+    api_url: &str,
+    api_key: Option<&str>,
+    request: ChatRequest,
+) -> Result<BoxStream<'static, Result<StreamItem>>> {
+    let uri = format!("{api_url}/v1/chat/completions");
+    let request = HttpRequest::builder()
+        .method(Method::POST)
+        .uri(uri)
+        .header("Content-Type", "application/json")
+        .when_some(api_key, |builder, api_key| {
+            builder.header("Authorization", format!("Bearer {api_key}"))
+        })
+        .body(AsyncBody::from(
+            serde_json::to_string(&request_body(&request)).map_err(anyhow::Error::from)?,
+        ))
+        .map_err(anyhow::Error::from)?;
+
+    let body = send_streaming(client, request).await?;
+    let mut decoder = ChunkDecoder::default();
+    let items = parse_sse_stream(body)
+        .flat_map(move |event| {
+            let items = match event.and_then(|event| decoder.push(&event.data)) {
+                Ok(items) => items.into_iter().map(Ok).collect(),
+                Err(error) => vec![Err(error)],
+            };
+            futures::stream::iter(items)
+        })
+        .boxed();
+    Ok(error_if_truncated(items, |item: &StreamItem| {
+        matches!(item, StreamItem::Done(_))
+    }))
+}
+
+fn request_body(request: &ChatRequest) -> Value {
+    let mut body = json!({
+        "model": request.model,
+        "messages": messages_body(&request.messages),
+        "stream": true,
+        // Token counts are only reported when asked for
+        "stream_options": { "include_usage": true },
+    });
+    if !request.tools.is_empty() {
+        body["tools"] = json!(request.tools);
+    }
+    if let Some(options) = &request.options {
+        if let Some(temperature) = options.temperature {
+            body["temperature"] = json!(temperature);
+        }
+        if let Some(top_p) = options.top_p {
+            body["top_p"] = json!(top_p);
+        }
+        if let Some(stop) = &options.stop {
+            body["stop"] = json!(stop);
+        }
+        // Ollama uses a negative `num_predict` for "no limit", which has no OpenAI equivalent
+        if let Some(max_tokens) = options.num_predict.filter(|max_tokens| *max_tokens > 0) {
+            body["max_tokens"] = json!(max_tokens);
+        }
+    }
+    body
+}
+
+/// Converts the messages, linking each tool result to its call by id as OpenAI requires.
+///
+/// Calls without an id get `call_{index}`, and results without one take the id of the
+/// first unanswered call to the same tool in the preceding assistant message.
+fn messages_body(messages: &[ChatMessage]) -> Vec<Value> {
+    let mut unanswered_calls = Vec::<(String, &str)>::new();
+    messages
+        .iter()
+        .map(|message| match message {
+            ChatMessage::System { content } => json!({ "role": "system", "content": content }),
+            ChatMessage::User { content, images } => json!({
+                "role": "user",
+                "content": content_body(content, images.as_deref()),
+            }),
+            ChatMessage::Assistant {
+                content,
+                tool_calls,
+                images,
+                ..
+            } => {
+                let mut message = json!({
+                    "role": "assistant",
+                    "content": content_body(content, images.as_deref()),
+                });
+                unanswered_calls = tool_calls
+                    .iter()
+                    .flatten()
+                    .enumerate()
+                    .map(|(index, call)| {
+                        let id = call.id.clone().unwrap_or_else(|| format!("call_{index}"));
+                        (id, call.function.name.as_str())
+                    })
+                    .collect();
+                if let Some(tool_calls) = tool_calls.as_ref().filter(|calls| !calls.is_empty()) {
+                    message["tool_calls"] = tool_calls
+                        .iter()
+                        .zip(&unanswered_calls)
+                        .map(|(call, (id, _))| {
+                            json!({
+                                "id": id,
+                                "type": "function",
+                                "function": {
+                                    "name": call.function.name,
+                                    // OpenAI passes arguments as a JSON-encoded string
+                                    "arguments": call.function.arguments.to_string(),
+                                },
+                            })
+                        })
+                        .collect();
+                }
+                message
+            }
+            ChatMessage::Tool {
+                tool_name,
+                content,
+                tool_call_id,
+            } => {
+                let position = unanswered_calls
+                    .iter()
+                    .position(|(id, name)| match tool_call_id {
+                        Some(tool_call_id) => id == tool_call_id,
+                        None => name == tool_name,
+                    });
+                let linked_id = position.map(|position| unanswered_calls.remove(position).0);
+                let mut message = json!({
+                    "role": "tool",
+                    "name": tool_name,
+                    "content": content,
+                });
+                if let Some(id) = tool_call_id.clone().or(linked_id) {
+                    message["tool_call_id"] = json!(id);
+                }
+                message
+            }
+        })
+        .collect()
+}
+
+/// Plain text, or a list of parts when the message carries base64 images
+fn content_body(content: &str, images: Option<&[String]>) -> Value {
+    match images {
+        Some(images) if !images.is_empty() => {
+            let mut parts = vec![json!({ "type": "text", "text": content })];
+            parts.extend(images.iter().map(|image| {
+                json!({
+                    "type": "image_url",
+                    "image_url": {
+                        "url": format!("data:{};base64,{image}", image_media_type(image)),
+                    },
+                })
+            }));
+            Value::Array(parts)
+        }
+        _ => json!(content),
+    }
+}
+
+/// Sniffs the media type of a base64 image from its leading bytes, assuming PNG when the
+/// format isn't recognized
+fn image_media_type(image: &str) -> &'static str {
+    // 16 base64 characters decode to the 12 bytes needed to recognize WebP
+    let prefix = image.get(..16).unwrap_or(image);
+    let bytes = base64::engine::general_purpose::STANDARD
+        .decode(prefix)
+        .unwrap_or_default();
+    if bytes.starts_with(b"\xFF\xD8\xFF") {
+        "image/jpeg"
+    } else if bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a") {
+        "image/gif"
+    } else if bytes.starts_with(b"RIFF") && bytes.get(8..12) == Some(&b"WEBP"[..]) {
+        "image/webp"
+    } else {
+        "image/png"
+    }
+}
+
+#[derive(Deserialize)]
+struct Chunk {
+    #[serde(default)]
+    choices: Vec<Choice>,
+    usage: Option<Usage>,
+    error: Option<ChunkError>,
+}
+
+#[derive(Deserialize)]
+struct Choice {
+    #[serde(default)]
+    delta: Delta,
+    finish_reason: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+struct Delta {
+    content: Option<String>,
+    /// Ollama's name for the model's thinking
+    reasoning: Option<String>,
+    /// What others such as vLLM and DeepSeek call the model's thinking. Some servers send
+    /// it alongside `reasoning`.
+    reasoning_content: Option<String>,
+    #[serde(default)]
+    tool_calls: Vec<ToolCallDelta>,
+}
+
+#[derive(Deserialize)]
+struct ToolCallDelta {
+    index: usize,
+    id: Option<String>,
+    function: Option<FunctionDelta>,
+}
+
+#[derive(Deserialize)]
+struct FunctionDelta {
+    name: Option<String>,
+    arguments: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Usage {
+    prompt_tokens: u64,
+    completion_tokens: u64,
+}
+
+#[derive(Deserialize)]
+struct ChunkError {
+    message: String,
+}
+
+/// Turns the `data:` payloads of a streamed completion into [`StreamItem`]s.
+///
+/// Tool calls arrive in fragments keyed by their index, so they are only emitted once the
+/// choice finishes and their arguments are complete.
+#[derive(Default)]
+struct ChunkDecoder {
+    tool_calls: BTreeMap<usize, PartialToolCall>,
+    stats: CompletionStats,
+}
+
+#[derive(Default)]
+struct PartialToolCall {
+    id: Option<String>,
+    name: String,
+    arguments: String,
+}
+
+impl ChunkDecoder {
+    fn push(&mut self, data: &str) -> Result<Vec<StreamItem>> {
+        if data == "[DONE]" {
+            let mut items = self.take_tool_calls()?;
+            items.push(StreamItem::Done(std::mem::take(&mut self.stats)));
+            return Ok(items);
+        }
+
+        let chunk: Chunk = serde_json::from_str(data)?;
+        if let Some(error) = chunk.error {
+            return Err(OllamaError::Api(error.message));
+        }
+        if let Some(usage) = chunk.usage {
+            self.stats.prompt_eval_count = Some(usage.prompt_tokens);
+            self.stats.eval_count = Some(usage.completion_tokens);
+        }
+
+        let mut items = Vec::new();
+        for choice in chunk.choices {
+            let Delta {
+                content,
+                reasoning,
+                reasoning_content,
+                tool_calls,
+            } = choice.delta;
+            let reasoning = [reasoning, reasoning_content]
+                .into_iter()
+                .flatten()
+                .find(|reasoning| !reasoning.is_empty());
+            if let Some(reasoning) = reasoning {
+                items.push(StreamItem::Thinking(reasoning));
+            }
+            if let Some(content) = content.filter(|content| !content.is_empty()) {
+                items.push(StreamItem::Content(content));
+            }
+            for call in tool_calls {
+                let partial = self.tool_calls.entry(call.index).or_default();
+                if let Some(id) = call.id {
+                    partial.id = Some(id);
+                }
+                if let Some(function) = call.function {
+                    if let Some(name) = function.name {
+                        partial.name.push_str(&name);
+                    }
+                    if let Some(arguments) = function.arguments {
+                        partial.arguments.push_str(&arguments);
+                    }
+                }
+            }
+            if let Some(finish_reason) = choice.finish_reason {
+                items.extend(self.take_tool_calls()?);
+                self.stats.done_reason = Some(DoneReason::from(finish_reason.as_str()));
+            }
+        }
+        Ok(items)
+    }
+
+    fn take_tool_calls(&mut self) -> Result<Vec<StreamItem>> {
+        std::mem::take(&mut self.tool_calls)
+            .into_values()
+            .map(|call| {
+                let arguments = if call.arguments.trim().is_empty() {
+                    json!({})
+                } else {
+                    serde_json::from_str(&call.arguments).map_err(|source| {
+                        OllamaError::InvalidToolArguments {
+                            name: call.name.clone(),
+                            source,
+                        }
+                    })?
+                };
+                Ok(StreamItem::ToolCall(OllamaToolCall {
+                    id: call.id,
+                    function: OllamaFunctionCall {
+                        name: call.name,
+                        arguments,
+                    },
+                }))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ChatOptions, KeepAlive, OLLAMA_API_URL, OllamaFunctionTool, OllamaTool};
+    use futures::{AsyncReadExt as _, TryStreamExt as _, executor::block_on};
+    use http_client::{FakeHttpClient, Response};
+
+    fn request() -> ChatRequest {
+        ChatRequest {
+            model: "llama3.2".to_string(),
+            messages: vec![
+                ChatMessage::System {
+                    content: "Be brief.".to_string(),
+                },
+                ChatMessage::User {
+                    content: "What's in this image, and what's the weather?".to_string(),
+                    images: Some(vec!["aGVsbG8=".to_string()]),
+                },
+            ],
+            stream: true,
+            keep_alive: KeepAlive::default(),
+            options: Some(ChatOptions {
+                num_predict: Some(128),
+                stop: Some(vec!["</s>".to_string()]),
+                ..ChatOptions::default()
+            }),
+            think: None,
+            tools: vec![OllamaTool::Function {
+                function: OllamaFunctionTool {
+                    name: "weather".to_string(),
+                    description: Some("Looks up the weather".to_string()),
+                    parameters: Some(json!({ "type": "object" })),
+                },
+            }],
+        }
+    }
+
+    #[test]
+    fn stream_openai_chat_end_to_end() {
+        let client = FakeHttpClient::create(|request| async move {
+            assert_eq!(request.method(), Method::POST);
+            assert_eq!(request.uri().path(), "/v1/chat/completions");
+            let mut body = String::new();
+            request.into_body().read_to_string(&mut body).await.unwrap();
+            let body: Value = serde_json::from_str(&body).unwrap();
+            assert_eq!(body["stream"], true);
+            assert_eq!(body["max_tokens"], 128);
+            assert_eq!(body["stop"], json!(["</s>"]));
+            assert_eq!(body["tools"][0]["type"], "function");
+            assert_eq!(body["tools"][0]["function"]["name"], "weather");
+            assert_eq!(
+                body["messages"][0],
+                json!({ "role": "system", "content": "Be brief." })
+            );
+            assert_eq!(
+                body["messages"][1]["content"],
+                json!([
+                    { "type": "text", "text": "What's in this image, and what's the weather?" },
+                    { "type": "image_url", "image_url": { "url": "data:image/png;base64,aGVsbG8=" } },
+                ])
+            );
+
+            let events = [
+                r#"data: {"choices":[{"index":0,"delta":{"role":"assistant","content":"A cat"},"finish_reason":null}]}"#,
+                "",
+                r#"data: {"choices":[{"index":0,"delta":{"content":"."},"finish_reason":null}]}"#,
+                "",
+                r#"data: {"choices":[{"index":0,"delta":{"tool_calls":[{"index":0,"id":"call_1","type":"function","function":{"name":"weather","arguments":"{\"city\":"}}]},"finish_reason":null}]}"#,
+                "",
+                r#"data: {"choices":[{"index":0,"delta":{"tool_calls":[{"index":0,"function":{"arguments":"\"Paris\"}"}}]},"finish_reason":null}]}"#,
+                "",
+                r#"data: {"choices":[{"index":0,"delta":{},"finish_reason":"tool_calls"}]}"#,
+                "",
+                r#"data: {"choices":[],"usage":{"prompt_tokens":31,"completion_tokens":9,"total_tokens":40}}"#,
+                "",
+                "data: [DONE]",
+                "",
+            ]
+            .join("\n");
+            Ok(Response::builder()
+                .status(200)
+                .header("Content-Type", "text/event-stream")
+                .body(AsyncBody::from(events))?)
+        });
+
+        let items = block_on(async {
+            stream_openai_chat(client.as_ref(), OLLAMA_API_URL, None, request())
+                .await
+                .unwrap()
+                .try_collect::<Vec<_>>()
+                .await
+        })
+        .unwrap();
+
+        assert_eq!(items.len(), 4);
+        assert!(matches!(&items[0], StreamItem::Content(content) if content == "A cat"));
+        assert!(matches!(&items[1], StreamItem::Content(content) if content == "."));
+        let StreamItem::ToolCall(call) = &items[2] else {
+            panic!("expected a tool call, got {:?}", items[2]);
+        };
+        assert_eq!(call.id.as_deref(), Some("call_1"));
+        assert_eq!(call.function.name, "weather");
+        assert_eq!(call.function.arguments, json!({ "city": "Paris" }));
+        let StreamItem::Done(stats) = &items[3] else {
+            panic!("expected the stream to finish, got {:?}", items[3]);
+        };
+        assert_eq!(
+            stats,
+            &CompletionStats {
+                prompt_eval_count: Some(31),
+                eval_count: Some(9),
+                done_reason: Some(DoneReason::ToolCalls),
+                skipped_lines: Vec::new(),
+            }
+        );
+    }
+
+    #[test]
+    fn stream_openai_chat_without_done_is_truncated() {
+        let client = FakeHttpClient::create(|_request| async move {
+            let events =
+                r#"data: {"choices":[{"index":0,"delta":{"content":"Hel"},"finish_reason":null}]}"#;
+            Ok(Response::builder()
+                .status(200)
+                .body(AsyncBody::from(format!("{events}\n\n")))?)
+        });
+
+        let items = block_on(async {
+            stream_openai_chat(client.as_ref(), OLLAMA_API_URL, None, request())
+                .await
+                .unwrap()
+                .collect::<Vec<_>>()
+                .await
+        });
+        assert!(matches!(&items[0], Ok(StreamItem::Content(content)) if content == "Hel"));
+        assert!(matches!(items[1], Err(OllamaError::Truncated)));
+    }
+
+    #[test]
+    fn stream_openai_chat_error_event() {
+        let client = FakeHttpClient::create(|_request| async move {
+            let events =
+                r#"data: {"error":{"message":"model \"llama3.2\" not found","type":"api_error"}}"#;
+            Ok(Response::builder()
+                .status(200)
+                .body(AsyncBody::from(format!("{events}\n\n")))?)
+        });
+
+        let items = block_on(async {
+            stream_openai_chat(client.as_ref(), OLLAMA_API_URL, None, request())
+                .await
+                .unwrap()
+                .collect::<Vec<_>>()
+                .await
+        });
+        assert!(matches!(
+            &items[0],
+            Err(OllamaError::Api(message)) if message == "model \"llama3.2\" not found"
+        ));
+    }
+
+    #[test]
+    fn tool_results_are_linked_to_their_calls() {
+        let tool_call = |id: Option<&str>, name: &str| OllamaToolCall {
+            id: id.map(ToString::to_string),
+            function: OllamaFunctionCall {
+                name: name.to_string(),
+                arguments: json!({}),
+            },
+        };
+        let tool_result = |tool_name: &str, tool_call_id: Option<&str>| ChatMessage::Tool {
+            tool_name: tool_name.to_string(),
+            content: "Sunny".to_string(),
+            tool_call_id: tool_call_id.map(ToString::to_string),
+        };
+        let messages = messages_body(&[
+            ChatMessage::Assistant {
+                content: String::new(),
+                tool_calls: Some(vec![
+                    tool_call(None, "weather"),
+                    tool_call(None, "time"),
+                    tool_call(Some("abc"), "weather"),
+                ]),
+                images: None,
+                thinking: None,
+            },
+            tool_result("weather", Some("abc")),
+            tool_result("time", None),
+            tool_result("weather", None),
+        ]);
+
+        let ids = messages[0]["tool_calls"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|call| call["id"].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, [json!("call_0"), json!("call_1"), json!("abc")]);
+        assert_eq!(messages[1]["tool_call_id"], "abc");
+        assert_eq!(messages[2]["tool_call_id"], "call_1");
+        assert_eq!(messages[3]["tool_call_id"], "call_0");
+    }
+
+    #[test]
+    fn image_media_type_is_sniffed() {
+        let encode = |bytes: &[u8]| base64::engine::general_purpose::STANDARD.encode(bytes);
+        assert_eq!(
+            image_media_type(&encode(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR")),
+            "image/png"
+        );
+        assert_eq!(
+            image_media_type(&encode(b"\xFF\xD8\xFF\xE0\0\x10JFIF\0")),
+            "image/jpeg"
+        );
+        assert_eq!(
+            image_media_type(&encode(b"RIFF\x24\0\0\0WEBPVP8 ")),
+            "image/webp"
+        );
+        assert_eq!(image_media_type(&encode(b"GIF89a\x01\0")), "image/gif");
+        assert_eq!(image_media_type("aGVsbG8="), "image/png");
+    }
+
+    #[test]
+    fn reasoning_content_is_thinking() {
+        let mut decoder = ChunkDecoder::default();
+        let items = decoder
+            .push(r#"{"choices":[{"index":0,"delta":{"reasoning_content":"Hmm"}}]}"#)
+            .unwrap();
+        assert!(matches!(&items[..], [StreamItem::Thinking(thinking)] if thinking == "Hmm"));
+    }
+
+    #[test]
+    fn reasoning_and_reasoning_content_yield_one_thought() {
+        let mut decoder = ChunkDecoder::default();
+        let items = decoder
+            .push(
+                r#"{"choices":[{"index":0,"delta":{"reasoning":"Hmm","reasoning_content":"Hmm"}}]}"#,
+            )
+            .unwrap();
+        assert!(matches!(&items[..], [StreamItem::Thinking(thinking)] if thinking == "Hmm"));
+
+        // An empty field doesn't hide the other one
+        let items = decoder
+            .push(r#"{"choices":[{"index":0,"delta":{"reasoning":"","reasoning_content":"Ok"}}]}"#)
+            .unwrap();
+        assert!(matches!(&items[..], [StreamItem::Thinking(thinking)] if thinking == "Ok"));
+    }
+}
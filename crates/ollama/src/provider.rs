@@ -0,0 +1,370 @@
+//! A chat backend abstraction, so callers can swap Ollama for a scripted fake in tests
+
+use futures::{
+    FutureExt as _, StreamExt as _, TryStreamExt as _, future::BoxFuture, stream::BoxStream,
+};
+use http_client::HttpClient;
+#[cfg(any(test, feature = "test-support"))]
+use parking_lot::Mutex;
+use std::sync::Arc;
+
+#[cfg(any(test, feature = "test-support"))]
+use crate::OllamaError;
+use crate::{
+    ChatRequest, ChatResponseDelta, Model, NoopMetrics, OllamaMetrics, Result, default_api_url,
+    get_models, load_model, stream_chat_completion_with_metrics,
+};
+
+/// How many `/api/show` requests [`OllamaProvider::models`] sends at once
+const MAX_CONCURRENT_MODEL_LOADS: usize = 5;
+
+/// The operations a chat backend needs to offer, so callers can swap Ollama for a scripted
+/// fake such as [`FakeChatProvider`] in tests
+pub trait ChatProvider: Send + Sync {
+    fn stream_chat(
+        &self,
+        request: ChatRequest,
+    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<ChatResponseDelta>>>>;
+
+    fn models(&self) -> BoxFuture<'static, Result<Vec<Model>>>;
+}
+
+/// A [`ChatProvider`] backed by an Ollama server
+///
+/// Build one per server and keep it around instead of creating a client for every request.
+/// All calls go through the same [`HttpClient`], so a pooling client such as
+/// `ReqwestClient` reuses its keep-alive connections rather than paying for a TCP/TLS
+/// handshake each time.
+pub struct OllamaProvider {
+    client: Arc<dyn HttpClient>,
+    api_url: String,
+    api_key: Option<String>,
+    metrics: Arc<dyn OllamaMetrics>,
+}
+
+impl OllamaProvider {
+    pub fn new(client: Arc<dyn HttpClient>, api_url: String, api_key: Option<String>) -> Self {
+        Self {
+            client,
+            api_url,
+            api_key,
+            metrics: Arc::new(NoopMetrics),
+        }
+    }
+
+    /// Reports the progress of every chat request to `metrics`
+    pub fn with_metrics(mut self, metrics: Arc<dyn OllamaMetrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Talks to the server from [`default_api_url`] through `client`, reusing it for every call
+    pub fn with_client(client: Arc<dyn HttpClient>) -> Self {
+        Self::new(client, default_api_url(), None)
+    }
+
+    pub fn client(&self) -> &Arc<dyn HttpClient> {
+        &self.client
+    }
+}
+
+impl ChatProvider for OllamaProvider {
+    fn stream_chat(
+        &self,
+        request: ChatRequest,
+    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<ChatResponseDelta>>>> {
+        let client = self.client.clone();
+        let api_url = self.api_url.clone();
+        let api_key = self.api_key.clone();
+        let metrics = self.metrics.clone();
+        async move {
+            stream_chat_completion_with_metrics(
+                client.as_ref(),
+                &api_url,
+                api_key.as_deref(),
+                request,
+                metrics,
+            )
+            .await
+        }
+        .boxed()
+    }
+
+    fn models(&self) -> BoxFuture<'static, Result<Vec<Model>>> {
+        let client = self.client.clone();
+        let api_url = self.api_url.clone();
+        let api_key = self.api_key.clone();
+        async move {
+            let listings = get_models(client.as_ref(), &api_url, api_key.as_deref()).await?;
+            // Ollama doesn't say which models only produce embeddings, but their names
+            // conventionally contain "-embed"
+            let loads = listings
+                .into_iter()
+                .filter(|listing| !listing.name.contains("-embed"))
+                .map(|listing| {
+                    let client = client.clone();
+                    let api_url = api_url.clone();
+                    let api_key = api_key.clone();
+                    async move {
+                        load_model(client.as_ref(), &api_url, api_key.as_deref(), &listing.name)
+                            .await
+                    }
+                });
+            // Each model needs an `/api/show` request for its capabilities, so only send a
+            // few at a time
+            let mut models = futures::stream::iter(loads)
+                .buffer_unordered(MAX_CONCURRENT_MODEL_LOADS)
+                .try_collect::<Vec<_>>()
+                .await?;
+            models.sort_by(|a, b| a.name.cmp(&b.name));
+            Ok(models)
+        }
+        .boxed()
+    }
+}
+
+/// A [`ChatProvider`] that replays scripted responses, one per chat request, and records
+/// the requests it receives
+#[cfg(any(test, feature = "test-support"))]
+#[derive(Default)]
+pub struct FakeChatProvider {
+    models: Vec<Model>,
+    responses: Mutex<std::collections::VecDeque<Vec<ChatResponseDelta>>>,
+    requests: Mutex<Vec<ChatRequest>>,
+}
+
+#[cfg(any(test, feature = "test-support"))]
+impl FakeChatProvider {
+    pub fn new(models: Vec<Model>) -> Self {
+        Self {
+            models,
+            ..Self::default()
+        }
+    }
+
+    /// Queues the deltas to stream back for the next chat request
+    pub fn push_response(&self, deltas: Vec<ChatResponseDelta>) {
+        self.responses.lock().push_back(deltas);
+    }
+
+    /// Returns the chat requests received so far, oldest first
+    pub fn take_requests(&self) -> Vec<ChatRequest> {
+        std::mem::take(&mut *self.requests.lock())
+    }
+}
+
+#[cfg(any(test, feature = "test-support"))]
+impl ChatProvider for FakeChatProvider {
+    fn stream_chat(
+        &self,
+        request: ChatRequest,
+    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<ChatResponseDelta>>>> {
+        let model = request.model.clone();
+        self.requests.lock().push(request);
+        let response = self.responses.lock().pop_front();
+        async move {
+            let deltas = response.ok_or_else(|| {
+                OllamaError::Other(anyhow::anyhow!("no response scripted for {model}"))
+            })?;
+            Ok(futures::stream::iter(deltas.into_iter().map(Ok)).boxed())
+        }
+        .boxed()
+    }
+
+    fn models(&self) -> BoxFuture<'static, Result<Vec<Model>>> {
+        futures::future::ready(Ok(self.models.clone())).boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ChatMessage, ChatOptions, ChatStreamStats, KeepAlive, OLLAMA_API_URL,
+        tests::{assistant_delta, delta_content, fake_ollama_server},
+    };
+    use futures::executor::block_on;
+    use http_client::{AsyncBody, FakeHttpClient, Response};
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    #[test]
+    fn ollama_provider_reports_metrics() {
+        #[derive(Default)]
+        struct RecordingMetrics {
+            connects: AtomicUsize,
+            first_tokens: AtomicUsize,
+            done: Mutex<Vec<ChatStreamStats>>,
+        }
+
+        impl OllamaMetrics for RecordingMetrics {
+            fn on_connect(&self, _elapsed: Duration) {
+                self.connects.fetch_add(1, Ordering::SeqCst);
+            }
+
+            fn on_first_token(&self, _elapsed: Duration) {
+                self.first_tokens.fetch_add(1, Ordering::SeqCst);
+            }
+
+            fn on_done(&self, stats: ChatStreamStats) {
+                self.done.lock().push(stats);
+            }
+        }
+
+        let metrics = Arc::new(RecordingMetrics::default());
+        let provider = OllamaProvider::new(
+            fake_ollama_server(),
+            OLLAMA_API_URL.to_string(),
+            Some("secret".to_string()),
+        )
+        .with_metrics(metrics.clone());
+
+        let deltas = block_on(async {
+            provider
+                .stream_chat(ChatRequest::for_model(
+                    &Model::new("llama3.2:latest", None, None, None, None, None),
+                    vec![ChatMessage::User {
+                        content: "Hi".to_string(),
+                        images: None,
+                    }],
+                    ChatOptions::default(),
+                ))
+                .await
+                .unwrap()
+                .try_collect::<Vec<_>>()
+                .await
+        })
+        .unwrap();
+        assert_eq!(deltas.len(), 3);
+
+        assert_eq!(metrics.connects.load(Ordering::SeqCst), 1);
+        assert_eq!(metrics.first_tokens.load(Ordering::SeqCst), 1);
+        let done = metrics.done.lock();
+        assert_eq!(done.len(), 1);
+        assert_eq!(done[0].prompt_tokens, Some(26));
+        assert_eq!(done[0].tokens, Some(2));
+    }
+
+    #[test]
+    fn ollama_provider_reuses_client() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let client: Arc<dyn HttpClient> = FakeHttpClient::create({
+            let requests = requests.clone();
+            move |_| {
+                requests.fetch_add(1, Ordering::SeqCst);
+                async move {
+                    Ok(Response::builder()
+                        .status(200)
+                        .body(AsyncBody::from(r#"{"models":[]}"#))?)
+                }
+            }
+        });
+        let provider = OllamaProvider::with_client(client.clone());
+
+        for _ in 0..3 {
+            assert!(block_on(provider.models()).unwrap().is_empty());
+        }
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+        assert!(Arc::ptr_eq(provider.client(), &client));
+    }
+
+    #[test]
+    fn ollama_provider_end_to_end() {
+        let provider: Arc<dyn ChatProvider> = Arc::new(OllamaProvider::new(
+            fake_ollama_server(),
+            OLLAMA_API_URL.to_string(),
+            Some("secret".to_string()),
+        ));
+
+        let models = block_on(provider.models()).unwrap();
+        assert_eq!(models[0].name, "llama3.2:latest");
+        assert_eq!(models[0].display_name.as_deref(), Some("llama3.2"));
+        // Capabilities come from `/api/show`
+        assert_eq!(models[0].supports_tools, Some(true));
+        assert_eq!(models[0].supports_vision, Some(false));
+
+        let deltas = block_on(async {
+            provider
+                .stream_chat(ChatRequest {
+                    model: "llama3.2:latest".to_string(),
+                    messages: vec![ChatMessage::User {
+                        content: "Hi".to_string(),
+                        images: None,
+                    }],
+                    stream: true,
+                    keep_alive: KeepAlive::default(),
+                    options: None,
+                    think: None,
+                    tools: vec![],
+                })
+                .await
+                .unwrap()
+                .try_collect::<Vec<_>>()
+                .await
+        })
+        .unwrap();
+        assert_eq!(
+            deltas.iter().map(delta_content).collect::<String>(),
+            "Hello!"
+        );
+    }
+
+    #[test]
+    fn fake_chat_provider_replays_scripted_responses() {
+        let fake = Arc::new(FakeChatProvider::new(vec![Model::new(
+            "llama3.2:latest",
+            None,
+            None,
+            None,
+            None,
+            None,
+        )]));
+        fake.push_response(vec![
+            assistant_delta("Hello", None),
+            assistant_delta(" there", None),
+        ]);
+        let provider: Arc<dyn ChatProvider> = fake.clone();
+        let request = |content: &str| ChatRequest {
+            model: "llama3.2:latest".to_string(),
+            messages: vec![ChatMessage::User {
+                content: content.to_string(),
+                images: None,
+            }],
+            stream: true,
+            keep_alive: KeepAlive::default(),
+            options: None,
+            think: None,
+            tools: vec![],
+        };
+
+        let models = block_on(provider.models()).unwrap();
+        assert_eq!(models.len(), 1);
+        assert_eq!(models[0].name, "llama3.2:latest");
+
+        let deltas = block_on(async {
+            provider
+                .stream_chat(request("Hi"))
+                .await
+                .unwrap()
+                .try_collect::<Vec<_>>()
+                .await
+        })
+        .unwrap();
+        assert_eq!(
+            deltas.iter().map(delta_content).collect::<Vec<_>>(),
+            ["Hello", " there"]
+        );
+
+        // Once the script runs out, further requests fail
+        assert!(block_on(provider.stream_chat(request("Again"))).is_err());
+
+        let requests = fake.take_requests();
+        assert_eq!(requests.len(), 2);
+        assert!(matches!(
+            &requests[1].messages[0],
+            ChatMessage::User { content, .. } if content == "Again"
+        ));
+    }
+}
@@ -0,0 +1,601 @@
+//! Parsers for the newline-delimited JSON and server-sent events that models stream
+
+use futures::{AsyncBufReadExt, AsyncRead, StreamExt, io::BufReader, stream::BoxStream};
+use serde::de::DeserializeOwned;
+use std::sync::{
+    Arc,
+    atomic::{AtomicBool, Ordering},
+};
+
+use crate::{ChatResponseDelta, ErrorResponse, OllamaError, Result};
+
+/// What [`parse_ndjson_stream`] does with a line that isn't valid JSON for the expected type
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OnParseError {
+    /// Log the line and carry on with the rest of the stream
+    #[default]
+    Skip,
+    /// Yield the parse error as an item of the stream
+    Fail,
+    /// Skip the line like [`OnParseError::Skip`], and for chat streams also report it in
+    /// [`ChatResponseDelta::skipped_lines`] of the final delta
+    Collect,
+}
+
+/// A line of newline-delimited JSON: either a value or a malformed line that was collected
+enum NdjsonLine<T> {
+    Value(T),
+    Skipped(String),
+}
+
+/// The longest line the stream parsers buffer before giving up with
+/// [`OllamaError::LineTooLong`]
+pub const MAX_LINE_BYTES: usize = 8 * 1024 * 1024;
+
+/// Parses the newline-delimited JSON that Ollama streams into a stream of values.
+///
+/// Lines are only decoded once complete, so reads that split a line (or a multi-byte
+/// character) are handled. Blank lines are ignored and lines that fail to parse are
+/// handled according to `on_parse_error`.
+pub fn parse_ndjson_stream<T, R>(
+    reader: R,
+    on_parse_error: OnParseError,
+) -> BoxStream<'static, Result<T>>
+where
+    T: DeserializeOwned + Send + 'static,
+    R: AsyncRead + Send + Unpin + 'static,
+{
+    parse_ndjson_stream_with_max_line(reader, on_parse_error, MAX_LINE_BYTES)
+}
+
+/// Like [`parse_ndjson_stream`], ending the stream with [`OllamaError::LineTooLong`] once a
+/// line exceeds `max_line_bytes` instead of [`MAX_LINE_BYTES`]
+pub fn parse_ndjson_stream_with_max_line<T, R>(
+    reader: R,
+    on_parse_error: OnParseError,
+    max_line_bytes: usize,
+) -> BoxStream<'static, Result<T>>
+where
+    T: DeserializeOwned + Send + 'static,
+    R: AsyncRead + Send + Unpin + 'static,
+{
+    parse_ndjson_lines(reader, on_parse_error, max_line_bytes)
+        .filter_map(|line| {
+            futures::future::ready(match line {
+                Ok(NdjsonLine::Value(value)) => Some(Ok(value)),
+                Ok(NdjsonLine::Skipped(_)) => None,
+                Err(error) => Some(Err(error)),
+            })
+        })
+        .boxed()
+}
+
+/// Parses a `/api/chat` response. With [`OnParseError::Collect`], the lines skipped along
+/// the way are attached to the final delta so they reach
+/// [`CompletionStats::skipped_lines`](crate::CompletionStats::skipped_lines)
+pub(crate) fn parse_chat_stream<R>(
+    reader: R,
+    on_parse_error: OnParseError,
+) -> BoxStream<'static, Result<ChatResponseDelta>>
+where
+    R: AsyncRead + Send + Unpin + 'static,
+{
+    let mut skipped_lines = Vec::new();
+    parse_ndjson_lines::<ChatResponseDelta, _>(reader, on_parse_error, MAX_LINE_BYTES)
+        .filter_map(move |line| {
+            let item = match line {
+                Ok(NdjsonLine::Value(mut delta)) => {
+                    if delta.done {
+                        delta.skipped_lines = std::mem::take(&mut skipped_lines);
+                    }
+                    Some(Ok(delta))
+                }
+                Ok(NdjsonLine::Skipped(line)) => {
+                    skipped_lines.push(line);
+                    None
+                }
+                Err(error) => Some(Err(error)),
+            };
+            futures::future::ready(item)
+        })
+        .boxed()
+}
+
+fn parse_ndjson_lines<T, R>(
+    reader: R,
+    on_parse_error: OnParseError,
+    max_line_bytes: usize,
+) -> BoxStream<'static, Result<NdjsonLine<T>>>
+where
+    T: DeserializeOwned + Send + 'static,
+    R: AsyncRead + Send + Unpin + 'static,
+{
+    read_lines(reader, max_line_bytes)
+        .filter_map(move |line| {
+            let item = match line {
+                Ok(line) if line.trim().is_empty() => None,
+                Ok(line) => match serde_json::from_str(&line) {
+                    Ok(value) => Some(Ok(NdjsonLine::Value(value))),
+                    Err(error) => match (ErrorResponse::parse(&line), on_parse_error) {
+                        (Some(api_error), _) => Some(Err(OllamaError::Api(api_error))),
+                        (None, OnParseError::Skip) => {
+                            log::warn!("Skipping malformed line from Ollama ({error}): {line}");
+                            None
+                        }
+                        (None, OnParseError::Collect) => {
+                            log::warn!("Skipping malformed line from Ollama ({error}): {line}");
+                            Some(Ok(NdjsonLine::Skipped(line)))
+                        }
+                        (None, OnParseError::Fail) => Some(Err(OllamaError::Parse(error))),
+                    },
+                },
+                Err(error) => Some(Err(error)),
+            };
+            futures::future::ready(item)
+        })
+        .boxed()
+}
+
+/// Splits `reader` into lines without their `\n` or `\r\n` endings. A line longer than
+/// `max_line_bytes` ends the stream with [`OllamaError::LineTooLong`], so a server that
+/// never sends a newline can't make it buffer without bound
+fn read_lines<R>(reader: R, max_line_bytes: usize) -> BoxStream<'static, Result<String>>
+where
+    R: AsyncRead + Send + Unpin + 'static,
+{
+    futures::stream::unfold(Some(BufReader::new(reader)), move |reader| async move {
+        let mut reader = reader?;
+        let mut line = Vec::new();
+        loop {
+            let available = match reader.fill_buf().await {
+                Ok(available) => available,
+                Err(error) => return Some((Err(OllamaError::Read(error)), None)),
+            };
+            if available.is_empty() {
+                if line.is_empty() {
+                    return None;
+                }
+                break;
+            }
+
+            let newline = available.iter().position(|&byte| byte == b'\n');
+            let end = newline.unwrap_or(available.len());
+            line.extend_from_slice(&available[..end]);
+            reader.consume_unpin(newline.map_or(end, |newline| newline + 1));
+
+            if line.len() > max_line_bytes {
+                return Some((Err(OllamaError::LineTooLong(max_line_bytes)), None));
+            }
+            if newline.is_some() {
+                break;
+            }
+        }
+
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+        let line = String::from_utf8(line).map_err(|error| OllamaError::Other(error.into()));
+        Some((line, Some(reader)))
+    })
+    .boxed()
+}
+
+/// Copies everything read from `reader`, such as the body of a streaming response, to the
+/// file at `path` while passing it through unchanged. The capture holds the raw JSON lines
+/// exactly as the server sent them, which makes it handy to attach to bug reports, and can
+/// be replayed with [`parse_ndjson_stream`].
+///
+/// The file is written from a background task, so reading never waits on the disk.
+#[cfg(feature = "record-streams")]
+pub async fn tee_to_file<R>(reader: R, path: &std::path::Path) -> Result<TeeReader<R>>
+where
+    R: AsyncRead + Send + Unpin + 'static,
+{
+    use futures::AsyncWriteExt as _;
+
+    let mut file = smol::fs::File::create(path)
+        .await
+        .map_err(anyhow::Error::from)?;
+    let (chunks_tx, mut chunks_rx) = futures::channel::mpsc::unbounded::<Vec<u8>>();
+    smol::spawn(async move {
+        while let Some(chunk) = chunks_rx.next().await {
+            // Flushing every chunk keeps the capture intact if Zed crashes mid-stream
+            if let Err(error) = async {
+                file.write_all(&chunk).await?;
+                file.flush().await
+            }
+            .await
+            {
+                log::warn!("Failed to record Ollama stream: {error}");
+                return;
+            }
+        }
+    })
+    .detach();
+
+    Ok(TeeReader {
+        reader,
+        chunks: chunks_tx,
+    })
+}
+
+/// A reader that sends a copy of everything it reads to the task started by [`tee_to_file`]
+#[cfg(feature = "record-streams")]
+pub struct TeeReader<R> {
+    reader: R,
+    chunks: futures::channel::mpsc::UnboundedSender<Vec<u8>>,
+}
+
+#[cfg(feature = "record-streams")]
+impl<R: AsyncRead + Unpin> AsyncRead for TeeReader<R> {
+    fn poll_read(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut [u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        let read = std::pin::Pin::new(&mut self.reader).poll_read(cx, buf);
+        if let std::task::Poll::Ready(Ok(len)) = read
+            && len > 0
+        {
+            self.chunks.unbounded_send(buf[..len].to_vec()).ok();
+        }
+        read
+    }
+}
+
+/// An event from a server-sent event stream
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SseEvent {
+    /// The `event:` field, which the sender leaves out for plain messages
+    pub event: Option<String>,
+    /// Every `data:` line of the event, joined with newlines
+    pub data: String,
+    pub id: Option<String>,
+}
+
+/// Assembles [`SseEvent`]s from the lines of a server-sent event stream, the framing
+/// OpenAI-compatible servers use in place of newline-delimited JSON
+#[derive(Debug, Default)]
+pub struct SseDecoder {
+    event: Option<String>,
+    data: Option<String>,
+    id: Option<String>,
+}
+
+impl SseDecoder {
+    /// Feeds a line, without its line ending, and returns the event it completes, if any
+    pub fn push_line(&mut self, line: &str) -> Option<SseEvent> {
+        if line.is_empty() {
+            return self.finish();
+        }
+        // Lines starting with a colon are comments, typically sent as keep-alive heartbeats
+        if line.starts_with(':') {
+            return None;
+        }
+        let (field, value) = line.split_once(':').unwrap_or((line, ""));
+        let value = value.strip_prefix(' ').unwrap_or(value);
+        match field {
+            "event" => self.event = Some(value.to_string()),
+            "data" => match &mut self.data {
+                Some(data) => {
+                    data.push('\n');
+                    data.push_str(value);
+                }
+                None => self.data = Some(value.to_string()),
+            },
+            "id" => self.id = Some(value.to_string()),
+            // `retry` and unknown fields don't affect how events are decoded
+            _ => {}
+        }
+        None
+    }
+
+    /// Returns the event still being assembled, for streams that end without the blank
+    /// line that normally terminates an event
+    pub fn finish(&mut self) -> Option<SseEvent> {
+        let event = self.event.take();
+        let id = self.id.take();
+        // Events without data are discarded, as they are by browsers
+        let data = self.data.take()?;
+        Some(SseEvent { event, data, id })
+    }
+}
+
+/// Parses a server-sent event stream into a stream of events
+pub fn parse_sse_stream<R>(reader: R) -> BoxStream<'static, Result<SseEvent>>
+where
+    R: AsyncRead + Send + Unpin + 'static,
+{
+    let mut decoder = SseDecoder::default();
+    read_lines(reader, MAX_LINE_BYTES)
+        .map(Some)
+        .chain(futures::stream::once(async { None }))
+        .filter_map(move |line| {
+            let event = match line {
+                Some(Ok(line)) => decoder.push_line(&line).map(Ok),
+                Some(Err(error)) => Some(Err(error)),
+                None => decoder.finish().map(Ok),
+            };
+            futures::future::ready(event)
+        })
+        .boxed()
+}
+
+/// Appends an [`OllamaError::Truncated`] error to a stream that ends before any of its
+/// items is done, so consumers can tell a crash or unloaded model from a clean finish
+pub(crate) fn error_if_truncated<T: Send + 'static>(
+    stream: BoxStream<'static, Result<T>>,
+    is_done: fn(&T) -> bool,
+) -> BoxStream<'static, Result<T>> {
+    let finished = Arc::new(AtomicBool::new(false));
+    let truncated = futures::stream::once({
+        let finished = finished.clone();
+        async move { (!finished.load(Ordering::Relaxed)).then_some(Err(OllamaError::Truncated)) }
+    })
+    .filter_map(futures::future::ready);
+
+    stream
+        .inspect(move |item| {
+            // A stream that stopped on a read error isn't reported as truncated as well, but
+            // other errors leave the stream running, so it may still be cut short afterwards
+            if item.as_ref().map_or_else(OllamaError::ends_stream, is_done) {
+                finished.store(true, Ordering::Relaxed);
+            }
+        })
+        .chain(truncated)
+        .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{StreamItem, into_items, tests::delta_content};
+    use futures::{AsyncReadExt as _, TryStreamExt as _, executor::block_on};
+    use serde_json::Value;
+    #[cfg(feature = "record-streams")]
+    use std::time::{Duration, Instant};
+
+    fn collect_deltas(reader: impl AsyncRead + Send + Unpin + 'static) -> Vec<ChatResponseDelta> {
+        block_on(
+            parse_ndjson_stream::<ChatResponseDelta, _>(reader, OnParseError::default())
+                .collect::<Vec<_>>(),
+        )
+        .into_iter()
+        .collect::<Result<Vec<_>>>()
+        .unwrap()
+    }
+
+    #[cfg(feature = "record-streams")]
+    #[test]
+    fn tee_to_file_records_raw_lines() {
+        // `total_duration` isn't modeled by `ChatResponseDelta`, but must still be recorded
+        let body = [
+            r#"{"model":"llama3.2","created_at":"2023-08-04T08:52:19Z","message":{"role":"assistant","content":"Hello"},"done":false}"#,
+            r#"{"model":"llama3.2","created_at":"2023-08-04T08:52:20Z","message":{"role":"assistant","content":" there"},"done_reason":"stop","done":true,"total_duration":4883583458,"eval_count":2}"#,
+        ]
+        .join("\n");
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("capture.ndjson");
+
+        let deltas = block_on(async {
+            let reader = tee_to_file(futures::io::Cursor::new(body.clone()), &path)
+                .await
+                .unwrap();
+            parse_ndjson_stream::<ChatResponseDelta, _>(reader, OnParseError::Fail)
+                .try_collect::<Vec<_>>()
+                .await
+        })
+        .unwrap();
+        assert_eq!(
+            deltas.iter().map(delta_content).collect::<Vec<_>>(),
+            ["Hello", " there"]
+        );
+
+        // The capture is written in the background, so give it a moment to catch up
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut recorded = String::new();
+        while Instant::now() < deadline {
+            recorded = std::fs::read_to_string(&path).unwrap();
+            if recorded == body {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(recorded, body);
+    }
+
+    #[test]
+    fn sse_stream_joins_multi_line_data() {
+        let body = [
+            "event: completion",
+            "id: 1",
+            "data: {\"content\":",
+            "data: \"Hello\"}",
+            "",
+            "data:no space after the colon",
+            "",
+            "data: trailing event without a blank line",
+        ]
+        .join("\n");
+
+        let events =
+            block_on(parse_sse_stream(futures::io::Cursor::new(body)).try_collect::<Vec<_>>())
+                .unwrap();
+        assert_eq!(
+            events,
+            [
+                SseEvent {
+                    event: Some("completion".to_string()),
+                    data: "{\"content\":\n\"Hello\"}".to_string(),
+                    id: Some("1".to_string()),
+                },
+                SseEvent {
+                    data: "no space after the colon".to_string(),
+                    ..SseEvent::default()
+                },
+                SseEvent {
+                    data: "trailing event without a blank line".to_string(),
+                    ..SseEvent::default()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn sse_decoder_ignores_comments() {
+        let mut decoder = SseDecoder::default();
+        assert_eq!(decoder.push_line(": heartbeat"), None);
+        assert_eq!(decoder.push_line(""), None);
+        assert_eq!(decoder.push_line("retry: 1000"), None);
+        assert_eq!(decoder.push_line("data: first"), None);
+        assert_eq!(decoder.push_line(":keep-alive"), None);
+        assert_eq!(decoder.push_line("data: second"), None);
+        assert_eq!(
+            decoder.push_line(""),
+            Some(SseEvent {
+                data: "first\nsecond".to_string(),
+                ..SseEvent::default()
+            })
+        );
+        // A record with only an event name carries no data and isn't dispatched
+        assert_eq!(decoder.push_line("event: ping"), None);
+        assert_eq!(decoder.push_line(""), None);
+        assert_eq!(decoder.finish(), None);
+    }
+
+    #[test]
+    fn ndjson_stream_skips_blank_and_malformed_lines() {
+        let body = [
+            r#"{"model":"llama3.2","created_at":"2023-08-04T08:52:19Z","message":{"role":"assistant","content":"Hello"},"done":false}"#,
+            "",
+            "   ",
+            r#"{"model":"llama3.2","created_at":"2023-08-04T08:52:19Z","message":{"role":"assis"#,
+            r#"{"model":"llama3.2","created_at":"2023-08-04T08:52:20Z","message":{"role":"assistant","content":" there"},"done":true}"#,
+        ]
+        .join("\n");
+
+        let deltas = collect_deltas(futures::io::Cursor::new(body));
+        assert_eq!(
+            deltas.iter().map(delta_content).collect::<Vec<_>>(),
+            ["Hello", " there"]
+        );
+        assert!(deltas[1].done);
+    }
+
+    /// Parses a chat response made of one malformed line followed by one good, final line
+    fn parse_chat_with_malformed_line(on_parse_error: OnParseError) -> Vec<Result<StreamItem>> {
+        let body = [
+            r#"{"model":"llama3.2","message":"#,
+            r#"{"model":"llama3.2","created_at":"2023-08-04T08:52:20Z","message":{"role":"assistant","content":"Hello"},"done_reason":"stop","done":true}"#,
+        ]
+        .join("\n");
+        block_on(
+            into_items(parse_chat_stream(
+                futures::io::Cursor::new(body),
+                on_parse_error,
+            ))
+            .collect::<Vec<_>>(),
+        )
+    }
+
+    #[test]
+    fn chat_stream_skips_malformed_lines() {
+        let items = parse_chat_with_malformed_line(OnParseError::Skip);
+        assert_eq!(items.len(), 2);
+        assert!(matches!(&items[0], Ok(StreamItem::Content(content)) if content == "Hello"));
+        let Ok(StreamItem::Done(stats)) = &items[1] else {
+            panic!("expected the stream to finish, got {:?}", items[1]);
+        };
+        assert!(stats.skipped_lines.is_empty());
+    }
+
+    #[test]
+    fn chat_stream_fails_on_malformed_lines() {
+        let items = parse_chat_with_malformed_line(OnParseError::Fail);
+        assert_eq!(items.len(), 3);
+        assert!(matches!(items[0], Err(OllamaError::Parse(_))));
+        assert!(matches!(&items[1], Ok(StreamItem::Content(content)) if content == "Hello"));
+        assert!(matches!(items[2], Ok(StreamItem::Done(_))));
+    }
+
+    #[test]
+    fn chat_stream_collects_malformed_lines() {
+        let items = parse_chat_with_malformed_line(OnParseError::Collect);
+        assert_eq!(items.len(), 2);
+        assert!(matches!(&items[0], Ok(StreamItem::Content(content)) if content == "Hello"));
+        let Ok(StreamItem::Done(stats)) = &items[1] else {
+            panic!("expected the stream to finish, got {:?}", items[1]);
+        };
+        assert_eq!(stats.skipped_lines, [r#"{"model":"llama3.2","message":"#]);
+    }
+
+    #[test]
+    fn ndjson_stream_line_too_long() {
+        // Without a limit this would buffer the whole 10 MiB
+        let endless_line = futures::io::repeat(b'a').take(10 * 1024 * 1024);
+        let items = block_on(
+            parse_ndjson_stream::<Value, _>(endless_line, OnParseError::Fail).collect::<Vec<_>>(),
+        );
+        assert_eq!(items.len(), 1);
+        assert!(matches!(
+            items[0],
+            Err(OllamaError::LineTooLong(MAX_LINE_BYTES))
+        ));
+
+        // Reading stops at the long line, even if valid lines follow it
+        let body = format!("{{\"n\":1}}\r\n{}\n{{\"n\":2}}\n", "x".repeat(64));
+        let items = block_on(
+            parse_ndjson_stream_with_max_line::<Value, _>(
+                futures::io::Cursor::new(body),
+                OnParseError::Fail,
+                16,
+            )
+            .collect::<Vec<_>>(),
+        );
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0].as_ref().unwrap(), &serde_json::json!({ "n": 1 }));
+        assert!(matches!(items[1], Err(OllamaError::LineTooLong(16))));
+    }
+
+    #[test]
+    fn ndjson_stream_error_line() {
+        let body = [
+            r#"{"model":"llama3.2","created_at":"2023-08-04T08:52:19Z","message":{"role":"assistant","content":"Hello"},"done":false}"#,
+            r#"{"error":"an error was encountered while running the model: unexpected EOF"}"#,
+        ]
+        .join("\n");
+
+        let items = block_on(
+            parse_ndjson_stream::<ChatResponseDelta, _>(
+                futures::io::Cursor::new(body),
+                OnParseError::default(),
+            )
+            .collect::<Vec<_>>(),
+        );
+        assert_eq!(items.len(), 2);
+        assert!(items[0].is_ok());
+        assert!(matches!(
+            &items[1],
+            Err(OllamaError::Api(error)) if error.contains("unexpected EOF")
+        ));
+    }
+
+    #[test]
+    fn ndjson_stream_joins_lines_split_across_reads() {
+        let line = r#"{"model":"llama3.2","created_at":"2023-08-04T08:52:19Z","message":{"role":"assistant","content":"héllo 👋"},"done":false}"#;
+        let body = format!("{line}\n{line}\n");
+        // Split into tiny reads, including in the middle of multi-byte characters
+        let chunks = body
+            .as_bytes()
+            .chunks(3)
+            .map(|chunk| Ok::<_, std::io::Error>(chunk.to_vec()))
+            .collect::<Vec<_>>();
+        let reader = futures::stream::iter(chunks).into_async_read();
+
+        let deltas = collect_deltas(reader);
+        assert_eq!(
+            deltas.iter().map(delta_content).collect::<Vec<_>>(),
+            ["héllo 👋", "héllo 👋"]
+        );
+    }
+}
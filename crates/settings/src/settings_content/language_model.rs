@@ -123,7 +123,7 @@ pub struct OllamaAvailableModel {
 pub enum KeepAlive {
     /// Keep model alive for N seconds
     Seconds(isize),
-    /// Keep model alive for a fixed duration. Accepts durations like "5m", "10m", "1h", "1d", etc.
+    /// Keep model alive for a fixed duration. Accepts durations like "5m", "10m", "1h", "1h30m", etc.
     Duration(String),
 }
 
@@ -132,6 +132,72 @@ impl KeepAlive {
     pub fn indefinite() -> Self {
         Self::Seconds(-1)
     }
+
+    /// Unload the model as soon as the request completes
+    pub fn unload() -> Self {
+        Self::Seconds(0)
+    }
+
+    /// Parses a keep-alive value the way Ollama interprets it: a bare integer is a number of
+    /// seconds (negative meaning indefinite), anything else must be a duration such as "5m".
+    pub fn parse(value: &str) -> anyhow::Result<Self> {
+        let value = value.trim();
+        if let Ok(seconds) = value.parse::<isize>() {
+            return Ok(Self::Seconds(seconds));
+        }
+        parse_duration_seconds(value)?;
+        Ok(Self::Duration(value.to_string()))
+    }
+
+    /// Returns the keep-alive time in whole seconds, or `None` if this is a duration Ollama
+    /// would reject.
+    pub fn to_seconds(&self) -> Option<isize> {
+        match self {
+            Self::Seconds(seconds) => Some(*seconds),
+            Self::Duration(duration) => parse_duration_seconds(duration)
+                .ok()
+                .map(|seconds| seconds as isize),
+        }
+    }
+}
+
+/// Parses a Go-style duration string (the syntax Ollama accepts for `keep_alive`), returning
+/// its length in seconds.
+fn parse_duration_seconds(duration: &str) -> anyhow::Result<f64> {
+    let (sign, mut rest) = match duration.strip_prefix('-') {
+        Some(rest) => (-1.0, rest),
+        None => (1.0, duration.strip_prefix('+').unwrap_or(duration)),
+    };
+    anyhow::ensure!(!rest.is_empty(), "invalid keep_alive duration {duration:?}");
+
+    let is_number_char = |c: char| c.is_ascii_digit() || c == '.';
+    let mut total_seconds = 0.0;
+    while !rest.is_empty() {
+        let number_len = rest
+            .find(|c: char| !is_number_char(c))
+            .unwrap_or(rest.len());
+        let number = rest[..number_len]
+            .parse::<f64>()
+            .map_err(|_| anyhow::anyhow!("invalid keep_alive duration {duration:?}"))?;
+        rest = &rest[number_len..];
+
+        let unit_len = rest.find(is_number_char).unwrap_or(rest.len());
+        let unit_seconds = match &rest[..unit_len] {
+            "ns" => 1e-9,
+            "us" | "µs" | "μs" => 1e-6,
+            "ms" => 1e-3,
+            "s" => 1.0,
+            "m" => 60.0,
+            "h" => 3600.0,
+            "" => anyhow::bail!("missing unit in keep_alive duration {duration:?}"),
+            unit => anyhow::bail!("unknown unit {unit:?} in keep_alive duration {duration:?}"),
+        };
+        rest = &rest[unit_len..];
+
+        total_seconds += number * unit_seconds;
+    }
+
+    Ok(sign * total_seconds)
 }
 
 impl Default for KeepAlive {
@@ -465,3 +531,39 @@ pub enum ModelMode {
         budget_tokens: Option<u32>,
     },
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keep_alive_parse() {
+        assert_eq!(
+            KeepAlive::parse("5m").unwrap(),
+            KeepAlive::Duration("5m".to_string())
+        );
+        assert_eq!(KeepAlive::parse("5m").unwrap().to_seconds(), Some(300));
+        assert_eq!(KeepAlive::parse("30s").unwrap().to_seconds(), Some(30));
+        assert_eq!(KeepAlive::parse("1h").unwrap().to_seconds(), Some(3600));
+        assert_eq!(KeepAlive::parse("1h30m").unwrap().to_seconds(), Some(5400));
+        assert_eq!(KeepAlive::parse("-1").unwrap(), KeepAlive::indefinite());
+        assert_eq!(KeepAlive::parse("600").unwrap(), KeepAlive::Seconds(600));
+
+        assert!(KeepAlive::parse("abc").is_err());
+        assert!(KeepAlive::parse("5").is_ok());
+        assert!(KeepAlive::parse("5x").is_err());
+        assert!(KeepAlive::parse("1d").is_err());
+        assert!(KeepAlive::parse("").is_err());
+    }
+
+    #[test]
+    fn test_keep_alive_to_seconds() {
+        assert_eq!(KeepAlive::Seconds(-1).to_seconds(), Some(-1));
+        assert_eq!(KeepAlive::Seconds(42).to_seconds(), Some(42));
+        assert_eq!(
+            KeepAlive::Duration("1m30s".to_string()).to_seconds(),
+            Some(90)
+        );
+        assert_eq!(KeepAlive::Duration("bogus".to_string()).to_seconds(), None);
+    }
+}